@@ -1,3 +1,4 @@
+use crate::http::grpc::GrpcStatus;
 use gethostname::gethostname;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
@@ -16,6 +17,7 @@ pub struct AgentData {
     process_id: u32,
     uptime: u64,
     watched_directories: Vec<PathBuf>,
+    hub_connection: Option<GrpcStatus>,
 }
 
 #[allow(dead_code)]
@@ -34,6 +36,7 @@ impl AgentData {
             process_id: sysinfo::get_current_pid().unwrap().as_u32(),
             uptime: sysinfo::System::uptime(),
             watched_directories: directories_watch_args,
+            hub_connection: None,
         }
     }
 
@@ -56,7 +59,8 @@ impl AgentData {
         );
     }
 
-    pub fn update(&mut self) {
+    pub fn update(&mut self, hub_connection: GrpcStatus) {
         self.uptime = sysinfo::System::uptime();
+        self.hub_connection = Some(hub_connection);
     }
 }
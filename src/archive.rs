@@ -0,0 +1,501 @@
+use crate::configuration::{ArchiveLimits, HashAlgorithm};
+use crate::content_type::{ContentType, FileCategory};
+use crate::error::FileInfoError;
+use crate::file_info::{digest_stream, FileInfo, StreamDigest};
+use crate::hash_cache::HashCache;
+use flate2::read::GzDecoder;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+// Offset and value of the magic of a POSIX tar header, to tell a compressed tarball from any
+// other compressed file
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_BLOCK_LEN: usize = 512;
+
+enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+    SevenZ,
+}
+
+impl ArchiveFormat {
+    fn from_mime_type(mime_type: &str) -> Option<Self> {
+        match mime_type {
+            "application/zip" => Some(Self::Zip),
+            "application/x-tar" => Some(Self::Tar),
+            "application/gzip" => Some(Self::TarGz),
+            "application/zstd" => Some(Self::TarZst),
+            "application/x-7z-compressed" => Some(Self::SevenZ),
+            _ => None,
+        }
+    }
+}
+
+/// Entries of a version of an archive, kept by the hash cache so that an archive is only read
+/// again once it changed
+#[derive(Debug, Clone)]
+pub enum Listing {
+    Entries(Arc<Vec<FileInfo>>),
+    /// The archive exceeds the limits, for this reason
+    OverLimit(String),
+}
+
+impl Listing {
+    /// The entries as found in `archive`, the listing may come from another path of the file.
+    fn entries_of(&self, archive: &FileInfo) -> Result<Vec<FileInfo>, FileInfoError> {
+        match self {
+            Listing::Entries(entries) => Ok(entries
+                .iter()
+                .map(|entry| FileInfo {
+                    path: archive.path.clone(),
+                    last_accessed: archive.last_accessed,
+                    ..entry.clone()
+                })
+                .collect()),
+            Listing::OverLimit(reason) => Err(FileInfoError::ArchiveLimit(
+                archive.path.clone(),
+                reason.clone(),
+            )),
+        }
+    }
+}
+
+/// What is left of the limits while an archive is being read. An entry past them fails the
+/// listing, the reason is kept to tell it from a corrupted archive.
+struct Budget {
+    entries: usize,
+    bytes: u64,
+    exceeded: Option<String>,
+}
+
+impl Budget {
+    fn new(archive: &FileInfo, limits: &ArchiveLimits) -> Self {
+        Self {
+            entries: limits.max_entries,
+            bytes: limits
+                .max_total_size
+                .min(archive.size.saturating_mul(limits.max_ratio)),
+            exceeded: None,
+        }
+    }
+
+    /// Hashes the next entry, reading at most one byte more than the bytes left.
+    fn digest(
+        &mut self,
+        reader: &mut dyn Read,
+        algorithm: HashAlgorithm,
+    ) -> std::io::Result<StreamDigest> {
+        if self.entries == 0 {
+            return Err(self.exceed(String::from("too many entries")));
+        }
+        self.entries -= 1;
+        let digest = digest_stream(&mut reader.take(self.bytes + 1), algorithm)?;
+        if digest.len > self.bytes {
+            return Err(self.exceed(String::from(
+                "too much data once decompressed, or compressed too much",
+            )));
+        }
+        self.bytes -= digest.len;
+        Ok(digest)
+    }
+
+    fn exceed(&mut self, reason: String) -> std::io::Error {
+        let err = std::io::Error::other(reason.clone());
+        self.exceeded = Some(reason);
+        err
+    }
+}
+
+/// Path of an entry relative to the archive, None for absolute paths or paths escaping it
+fn entry_path(name: &Path) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+fn entry_info(
+    archive: &FileInfo,
+    inner_path: PathBuf,
+    last_modified: Option<SystemTime>,
+    digest: StreamDigest,
+) -> FileInfo {
+    let content_type = ContentType::detect(&digest.head).refine_with_path(&inner_path);
+    FileInfo {
+        path: archive.path.clone(),
+        inner_path: Some(inner_path),
+        size: digest.len,
+        hash: Some(digest.hash),
+        hash_algorithm: archive.hash_algorithm,
+        mime_type: Some(content_type.mime_type),
+        category: content_type.category,
+        last_modified: last_modified.unwrap_or(archive.last_modified),
+        last_accessed: archive.last_accessed,
+        ..Default::default()
+    }
+}
+
+fn tar_entries(
+    archive: &FileInfo,
+    reader: impl Read,
+    budget: &mut Budget,
+) -> std::io::Result<Vec<FileInfo>> {
+    let mut entries = Vec::new();
+    for entry in tar::Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let inner_path = match entry_path(&entry.path()?) {
+            Some(inner_path) => inner_path,
+            None => continue,
+        };
+        let last_modified = entry
+            .header()
+            .mtime()
+            .ok()
+            .map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+        let digest = budget.digest(&mut entry, archive.hash_algorithm)?;
+        entries.push(entry_info(archive, inner_path, last_modified, digest));
+    }
+    Ok(entries)
+}
+
+/// Whether the decompressed stream starts with a tar header
+fn is_tarball(mut reader: impl Read) -> bool {
+    let mut header = [0; TAR_BLOCK_LEN];
+    reader.read_exact(&mut header).is_ok() && header[TAR_MAGIC_OFFSET..].starts_with(TAR_MAGIC)
+}
+
+fn zip_entries(
+    archive: &FileInfo,
+    file: File,
+    budget: &mut Budget,
+) -> zip::result::ZipResult<Vec<FileInfo>> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(file))?;
+    let mut entries = Vec::new();
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let inner_path = match entry_path(Path::new(entry.name())) {
+            Some(inner_path) => inner_path,
+            None => continue,
+        };
+        let last_modified = entry.last_modified().to_time().ok().map(SystemTime::from);
+        let digest = budget.digest(&mut entry, archive.hash_algorithm)?;
+        entries.push(entry_info(archive, inner_path, last_modified, digest));
+    }
+    Ok(entries)
+}
+
+fn seven_zip_entries(
+    archive: &FileInfo,
+    budget: &mut Budget,
+) -> Result<Vec<FileInfo>, sevenz_rust::Error> {
+    let mut reader =
+        sevenz_rust::SevenZReader::open(&archive.path, sevenz_rust::Password::empty())?;
+    let mut entries = Vec::new();
+    // Solid archives can only be decompressed in order, so every entry is read as it comes
+    reader.for_each_entries(|entry, content| {
+        if entry.is_directory() {
+            return Ok(true);
+        }
+        if let Some(inner_path) = entry_path(Path::new(entry.name())) {
+            let last_modified = entry
+                .has_last_modified_date
+                .then(|| SystemTime::from(entry.last_modified_date));
+            let digest = budget.digest(content, archive.hash_algorithm)?;
+            entries.push(entry_info(archive, inner_path, last_modified, digest));
+        }
+        Ok(true)
+    })?;
+    Ok(entries)
+}
+
+/// Lists and hashes the files inside an archive. Files that are not archives, including
+/// compressed files that are not tarballs, have no entries. The listing of an unchanged archive
+/// comes from the hash cache.
+pub fn list_entries(
+    archive: &FileInfo,
+    hash_cache: &HashCache,
+    limits: &ArchiveLimits,
+) -> Result<Vec<FileInfo>, FileInfoError> {
+    let format = match (archive.category, &archive.mime_type) {
+        (FileCategory::Archive, Some(mime_type)) => ArchiveFormat::from_mime_type(mime_type),
+        _ => None,
+    };
+    let format = match format {
+        Some(format) => format,
+        None => return Ok(Vec::new()),
+    };
+    let md = fs::metadata(&archive.path).ok();
+    if let Some(listing) = md.as_ref().and_then(|md| hash_cache.archive_listing(md)) {
+        return listing.entries_of(archive);
+    }
+
+    let mut budget = Budget::new(archive, limits);
+    // Sorted like the scanned files, so that reconciliations meet the entries in path order
+    let result = match read_entries(archive, format, &mut budget) {
+        Ok(mut entries) => {
+            entries.sort_by(|a, b| a.inner_path.cmp(&b.inner_path));
+            Ok(entries)
+        }
+        Err(_) if budget.exceeded.is_some() => Err(FileInfoError::ArchiveLimit(
+            archive.path.clone(),
+            budget.exceeded.unwrap_or_default(),
+        )),
+        result => result,
+    };
+    // A failure to read the archive may not happen again, it is not cached
+    let listing = match &result {
+        Ok(entries) => Listing::Entries(Arc::new(entries.clone())),
+        Err(FileInfoError::ArchiveLimit(_, reason)) => Listing::OverLimit(reason.clone()),
+        Err(_) => return result,
+    };
+    if let Some(md) = md {
+        hash_cache.set_archive_listing(&md, listing);
+    }
+    result
+}
+
+fn read_entries(
+    archive: &FileInfo,
+    format: ArchiveFormat,
+    budget: &mut Budget,
+) -> Result<Vec<FileInfo>, FileInfoError> {
+    let path = &archive.path;
+    let io_error = |e| FileInfoError::Io(path.clone(), e);
+    let archive_error =
+        |e: &dyn std::fmt::Display| FileInfoError::Archive(path.clone(), e.to_string());
+    let open = || File::open(path).map_err(io_error);
+
+    match format {
+        ArchiveFormat::Zip => zip_entries(archive, open()?, budget).map_err(|e| archive_error(&e)),
+        ArchiveFormat::Tar => {
+            tar_entries(archive, BufReader::new(open()?), budget).map_err(io_error)
+        }
+        ArchiveFormat::TarGz => {
+            if !is_tarball(GzDecoder::new(open()?)) {
+                return Ok(Vec::new());
+            }
+            tar_entries(archive, GzDecoder::new(BufReader::new(open()?)), budget).map_err(io_error)
+        }
+        ArchiveFormat::TarZst => {
+            if !is_tarball(zstd::Decoder::new(open()?).map_err(io_error)?) {
+                return Ok(Vec::new());
+            }
+            tar_entries(
+                archive,
+                zstd::Decoder::new(open()?).map_err(io_error)?,
+                budget,
+            )
+            .map_err(io_error)
+        }
+        ArchiveFormat::SevenZ => seven_zip_entries(archive, budget).map_err(|e| archive_error(&e)),
+    }
+}
+
+/// The file followed by its entries when it is an archive, an unreadable archive is reported
+/// without entries.
+pub fn with_entries(
+    info: FileInfo,
+    hash_cache: &HashCache,
+    limits: &ArchiveLimits,
+) -> Vec<FileInfo> {
+    let entries = match list_entries(&info, hash_cache, limits) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("{err}");
+            Vec::new()
+        }
+    };
+    if !entries.is_empty() {
+        info!(
+            "Found {} entries in the archive {}",
+            entries.len(),
+            info.path.display()
+        );
+    }
+    let mut files = Vec::with_capacity(entries.len() + 1);
+    files.push(info);
+    files.extend(entries);
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_cache::HashCache;
+    use std::fs;
+    use std::io::Write;
+
+    fn archive_file_info(path: &Path) -> FileInfo {
+        crate::file_info::create_file_info(path, &HashCache::default(), false).unwrap()
+    }
+
+    fn entry_summary(entries: &[FileInfo]) -> Vec<(PathBuf, u64, Option<String>)> {
+        entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.inner_path.clone().unwrap(),
+                    entry.size,
+                    entry.hash.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists_zip_tar_and_7z_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let unpacked = dir.path().join("unpacked");
+        fs::create_dir_all(unpacked.join("sub")).unwrap();
+        fs::write(unpacked.join("a.txt"), "first file").unwrap();
+        fs::write(unpacked.join("sub/b.txt"), "second file").unwrap();
+        let hash_of = |name: &str| archive_file_info(&unpacked.join(name)).hash;
+        let expected = vec![
+            (PathBuf::from("a.txt"), 10, hash_of("a.txt")),
+            (PathBuf::from("sub/b.txt"), 11, hash_of("sub/b.txt")),
+        ];
+
+        let zip_path = dir.path().join("files.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        for name in ["a.txt", "sub/b.txt"] {
+            zip.start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(&fs::read(unpacked.join(name)).unwrap())
+                .unwrap();
+        }
+        zip.finish().unwrap();
+
+        let tar_gz_path = dir.path().join("files.tar.gz");
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+            File::create(&tar_gz_path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        tar.append_dir_all(".", &unpacked).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let seven_zip_path = dir.path().join("files.7z");
+        sevenz_rust::compress_to_path(&unpacked, &seven_zip_path).unwrap();
+
+        for path in [zip_path, tar_gz_path, seven_zip_path] {
+            let archive = archive_file_info(&path);
+            assert_eq!(archive.category, FileCategory::Archive);
+            let mut entries =
+                list_entries(&archive, &HashCache::default(), &ArchiveLimits::default()).unwrap();
+            entries.sort_by(|a, b| a.inner_path.cmp(&b.inner_path));
+            assert_eq!(entry_summary(&entries), expected, "{}", path.display());
+            assert_eq!(entries[0].full_path(), archive.path.join("a.txt"));
+        }
+    }
+
+    #[test]
+    fn compressed_files_are_not_always_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&[b'x'; 2000]).unwrap();
+        encoder.finish().unwrap();
+
+        let info = archive_file_info(&path);
+        assert_eq!(info.mime_type.as_deref(), Some("application/gzip"));
+        let (hash_cache, limits) = (HashCache::default(), ArchiveLimits::default());
+        assert!(list_entries(&info, &hash_cache, &limits)
+            .unwrap()
+            .is_empty());
+        assert_eq!(with_entries(info, &hash_cache, &limits).len(), 1);
+    }
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, content) in entries {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn rejects_archives_over_the_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bomb.zip");
+        write_zip(&path, &[("zeros", &[0; 1 << 20]), ("other", b"other")]);
+        let archive = archive_file_info(&path);
+        let hash_cache = HashCache::default();
+        let over_limit = |limits: ArchiveLimits| {
+            matches!(
+                list_entries(&archive, &hash_cache, &limits),
+                Err(FileInfoError::ArchiveLimit(..))
+            )
+        };
+
+        assert!(!over_limit(ArchiveLimits {
+            max_ratio: 1000,
+            ..Default::default()
+        }));
+        // A megabyte of zeros compresses to a few kilobytes
+        assert!(over_limit(ArchiveLimits::default()));
+        assert!(over_limit(ArchiveLimits {
+            max_entries: 1,
+            max_ratio: 1000,
+            ..Default::default()
+        }));
+        assert!(over_limit(ArchiveLimits {
+            max_total_size: 1 << 20,
+            max_ratio: 1000,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn reuses_the_listing_of_an_unchanged_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("files.zip");
+        write_zip(&path, &[("a.txt", b"first file")]);
+        let hash_cache = HashCache::default();
+        let limits = ArchiveLimits::default();
+        let archive = crate::file_info::create_file_info(&path, &hash_cache, false).unwrap();
+
+        let entries = list_entries(&archive, &hash_cache, &limits).unwrap();
+        let md = fs::metadata(&path).unwrap();
+        assert!(matches!(
+            hash_cache.archive_listing(&md),
+            Some(Listing::Entries(listed)) if listed.len() == 1
+        ));
+        // The cached listing is used as long as the archive does not change
+        hash_cache.set_archive_listing(&md, Listing::Entries(Arc::new(Vec::new())));
+        assert!(list_entries(&archive, &hash_cache, &limits)
+            .unwrap()
+            .is_empty());
+
+        write_zip(
+            &path,
+            &[("a.txt", b"first file"), ("b.txt", b"second file")],
+        );
+        let archive = crate::file_info::create_file_info(&path, &hash_cache, false).unwrap();
+        assert_eq!(
+            list_entries(&archive, &hash_cache, &limits).unwrap().len(),
+            entries.len() + 1
+        );
+    }
+}
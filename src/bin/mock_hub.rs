@@ -0,0 +1,36 @@
+use std::net::SocketAddr;
+use tidybee_agent::configuration::Configuration;
+use tidybee_agent::mock_hub::MockHub;
+use tracing::{error, info, Level};
+
+/// Serves the mock Hub on the ports of the agent configuration until interrupted.
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_target(false)
+        .with_max_level(Level::INFO)
+        .compact()
+        .init();
+
+    let config = match Configuration::init() {
+        Ok(config) => config.hub_config,
+        Err(err) => {
+            error!("Error: {}", err);
+            return;
+        }
+    };
+    let http_address = SocketAddr::from(([0, 0, 0, 0], config.port.parse().unwrap_or(7001)));
+    let grpc_address = SocketAddr::from(([0, 0, 0, 0], config.grpc_server.port));
+
+    let _hub = match MockHub::start(http_address, grpc_address, &config).await {
+        Ok(hub) => hub,
+        Err(err) => {
+            error!("Error: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        error!("Error: {}", err);
+    }
+    info!("Mock Hub stopped");
+}
@@ -12,9 +12,132 @@ pub struct AgentData {
     pub minimal_version: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[serde(rename = "xxh3-64")]
+    Xxh3_64,
+    #[default]
+    #[serde(rename = "xxh3-128")]
+    Xxh3_128,
+    #[serde(rename = "blake3")]
+    Blake3,
+    #[serde(rename = "sha256")]
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Name sent to the Hub alongside the digest
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Xxh3_64 => "xxh3-64",
+            HashAlgorithm::Xxh3_128 => "xxh3-128",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileSystemInterfaceConfig {
     pub dir: Vec<PathBuf>,
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    /// Threads hashing files during a scan, 0 uses one thread per core
+    #[serde(default)]
+    pub scan_threads: usize,
+    /// How long a file must keep the same size and modification time before a change is sent,
+    /// unless its writer closing it is seen first. 0 sends every change right away
+    #[serde(default = "default_stable_write_quiet_period_ms")]
+    pub stable_write_quiet_period_ms: u64,
+    /// List the files inside zip, tar, tar.gz, tar.zst and 7z archives and report them as
+    /// entries of the archive
+    #[serde(default)]
+    pub archive_introspection: bool,
+    #[serde(default)]
+    pub archive_limits: ArchiveLimits,
+    /// Follow symbolic links as the file or directory they point to, instead of reporting the
+    /// links themselves. Files in a linked directory are reported at the path the link resolves to
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Gitignore rules applied to every watched directory, on top of the `.tidyignore` files
+    #[serde(default = "default_ignore_patterns")]
+    pub ignore_patterns: Vec<String>,
+    #[serde(default)]
+    pub directory_ignore_patterns: Vec<DirectoryIgnorePatterns>,
+    /// Directories the Hub may ask to watch besides the watched ones, along with everything
+    /// under them
+    #[serde(default)]
+    pub allowed_watch_roots: Vec<PathBuf>,
+}
+
+/// Limits on what is read from a single archive, an archive exceeding them is reported without
+/// its entries
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct ArchiveLimits {
+    pub max_entries: usize,
+    /// Total size of the decompressed entries
+    pub max_total_size: u64,
+    /// Decompressed bytes per byte of the archive
+    pub max_ratio: u64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 10000,
+            max_total_size: 4 * 1024 * 1024 * 1024,
+            max_ratio: 100,
+        }
+    }
+}
+
+/// Gitignore rules of a single watched directory
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DirectoryIgnorePatterns {
+    pub dir: PathBuf,
+    pub patterns: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    Block,
+    Coalesce,
+    Spill,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EventPipelineConfig {
+    pub capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StateConfig {
+    pub dir: PathBuf,
+    /// How often the inventory and the hash cache are written to disk when they changed, they
+    /// are also written at shutdown
+    #[serde(default = "default_save_interval_ms")]
+    pub save_interval_ms: u64,
+}
+
+fn default_save_interval_ms() -> u64 {
+    30000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupMode {
+    /// Send every file as created
+    Full,
+    /// Only send what changed since the last run
+    Reconcile,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StartupConfig {
+    pub mode: StartupMode,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -23,12 +146,40 @@ pub struct ServerConfig {
     pub log_level: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TlsConfig {
+    pub ca_certificate: Option<PathBuf>,
+    pub client_certificate: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub server_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GrpcCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GrpcServerConfig {
     pub host: String,
     pub protocol: String,
     pub port: u16,
     pub log_level: String,
+    pub batch_size: usize,
+    pub flush_interval_ms: u64,
+    pub reconnect_base_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+    #[serde(default)]
+    pub compression: GrpcCompression,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// Send the report of every scan to the Hub, it is always available on /scan_report
+    #[serde(default)]
+    pub send_scan_reports: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -40,6 +191,8 @@ pub struct HubConfig {
     pub disconnect_path: String,
     pub connection_attempt_limit: u32,
     pub grpc_server: GrpcServerConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
 }
 
 #[derive(Debug, Serialize, Clone, Deserialize)]
@@ -52,11 +205,31 @@ pub struct LoggerConfig {
 pub struct Configuration {
     pub agent_data: AgentData,
     pub filesystem_interface_config: FileSystemInterfaceConfig,
+    pub state_config: StateConfig,
+    pub startup_config: StartupConfig,
+    pub event_pipeline_config: EventPipelineConfig,
     pub server_config: ServerConfig,
     pub logger_config: LoggerConfig,
     pub hub_config: HubConfig,
 }
 
+fn default_stable_write_quiet_period_ms() -> u64 {
+    2000
+}
+
+fn default_ignore_patterns() -> Vec<String> {
+    [
+        ".git/",
+        "node_modules/",
+        "target/",
+        "__pycache__/",
+        ".cache/",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
 impl Default for Configuration {
     fn default() -> Self {
         Self {
@@ -66,6 +239,26 @@ impl Default for Configuration {
             },
             filesystem_interface_config: FileSystemInterfaceConfig {
                 dir: vec![[r"tests", "assets", "test_folder"].iter().collect()],
+                hash_algorithm: HashAlgorithm::Xxh3_128,
+                scan_threads: 0,
+                stable_write_quiet_period_ms: default_stable_write_quiet_period_ms(),
+                archive_introspection: false,
+                archive_limits: ArchiveLimits::default(),
+                follow_symlinks: false,
+                ignore_patterns: default_ignore_patterns(),
+                directory_ignore_patterns: Vec::new(),
+                allowed_watch_roots: Vec::new(),
+            },
+            state_config: StateConfig {
+                dir: PathBuf::from("state"),
+                save_interval_ms: default_save_interval_ms(),
+            },
+            startup_config: StartupConfig {
+                mode: StartupMode::Reconcile,
+            },
+            event_pipeline_config: EventPipelineConfig {
+                capacity: 10000,
+                overflow_policy: OverflowPolicy::Coalesce,
             },
             server_config: ServerConfig {
                 address: String::from("0.0.0.0:8111"),
@@ -83,7 +276,15 @@ impl Default for Configuration {
                     protocol: String::from("http"),
                     port: 5057,
                     log_level: String::from("info"),
+                    batch_size: 1000,
+                    flush_interval_ms: 500,
+                    reconnect_base_delay_ms: 500,
+                    reconnect_max_delay_ms: 60000,
+                    compression: GrpcCompression::None,
+                    tls: TlsConfig::default(),
+                    send_scan_reports: false,
                 },
+                tls: TlsConfig::default(),
             },
             logger_config: LoggerConfig {
                 term_level: String::from("debug"),
@@ -110,6 +311,34 @@ impl Configuration {
             .build()
             .unwrap();
         let config: Configuration = builder.try_deserialize().unwrap();
+        config.validate()?;
         Ok(config)
     }
+
+    /// Rejects the settings the agent cannot run with.
+    fn validate(&self) -> Result<(), AgentError> {
+        let grpc_server = &self.hub_config.grpc_server;
+        let settings = [
+            (
+                "hub_config.grpc_server.batch_size",
+                grpc_server.batch_size as u64,
+            ),
+            (
+                "hub_config.grpc_server.flush_interval_ms",
+                grpc_server.flush_interval_ms,
+            ),
+            (
+                "state_config.save_interval_ms",
+                self.state_config.save_interval_ms,
+            ),
+        ];
+        for (name, value) in settings {
+            if value == 0 {
+                return Err(AgentError::InvalidSetting(format!(
+                    "{name} must be at least 1"
+                )));
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,137 @@
+use infer::MatcherType;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Bytes needed at the start of a file to recognize its type
+pub const CONTENT_TYPE_HEAD_LEN: usize = 8192;
+
+const TEXT_MIME_TYPE: &str = "text/plain";
+const BINARY_MIME_TYPE: &str = "application/octet-stream";
+const EMPTY_MIME_TYPE: &str = "inode/x-empty";
+
+const CODE_EXTENSIONS: &[&str] = &[
+    "c", "cc", "cpp", "cs", "css", "go", "h", "hpp", "java", "js", "json", "jsx", "kt", "lua",
+    "php", "pl", "py", "rb", "rs", "scala", "sh", "sql", "swift", "toml", "ts", "tsx", "yaml",
+    "yml",
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileCategory {
+    #[default]
+    Other,
+    Document,
+    Image,
+    Video,
+    Audio,
+    Archive,
+    Code,
+    Executable,
+    Font,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    pub mime_type: String,
+    pub category: FileCategory,
+}
+
+impl ContentType {
+    /// Recognizes the type from the magic bytes at the start of the file. Files without magic
+    /// bytes are told apart as text or binary.
+    pub fn detect(head: &[u8]) -> Self {
+        if let Some(kind) = infer::get(head) {
+            let category = match kind.matcher_type() {
+                MatcherType::App => FileCategory::Executable,
+                MatcherType::Archive => FileCategory::Archive,
+                MatcherType::Audio => FileCategory::Audio,
+                MatcherType::Book | MatcherType::Doc => FileCategory::Document,
+                MatcherType::Font => FileCategory::Font,
+                MatcherType::Image => FileCategory::Image,
+                MatcherType::Text => FileCategory::Code,
+                MatcherType::Video => FileCategory::Video,
+                MatcherType::Custom => FileCategory::Other,
+            };
+            return Self {
+                mime_type: String::from(kind.mime_type()),
+                category,
+            };
+        }
+
+        let mime_type = if head.is_empty() {
+            EMPTY_MIME_TYPE
+        } else if is_text(head) {
+            TEXT_MIME_TYPE
+        } else {
+            BINARY_MIME_TYPE
+        };
+        Self {
+            mime_type: String::from(mime_type),
+            category: FileCategory::Other,
+        }
+    }
+
+    /// Plain text has no magic bytes, its category comes from the extension: source code and
+    /// configuration files are code, anything else is a document.
+    pub fn refine_with_path(mut self, path: &Path) -> Self {
+        if self.mime_type == TEXT_MIME_TYPE {
+            let is_code = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| {
+                    CODE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+                });
+            self.category = if is_code {
+                FileCategory::Code
+            } else {
+                FileCategory::Document
+            };
+        }
+        self
+    }
+}
+
+/// UTF-8 without NUL bytes, the head may end in the middle of a character
+fn is_text(head: &[u8]) -> bool {
+    if head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_bytes_win_over_the_extension() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let content_type = ContentType::detect(png).refine_with_path(Path::new("report.pdf"));
+        assert_eq!(content_type.mime_type, "image/png");
+        assert_eq!(content_type.category, FileCategory::Image);
+
+        let zip = b"PK\x03\x04\x14\0\0\0\x08\0";
+        assert_eq!(ContentType::detect(zip).category, FileCategory::Archive);
+    }
+
+    #[test]
+    fn text_category_comes_from_the_extension() {
+        let text = ContentType::detect("fn main() {}\n".as_bytes());
+        assert_eq!(text.mime_type, TEXT_MIME_TYPE);
+        assert_eq!(
+            text.clone().refine_with_path(Path::new("main.rs")).category,
+            FileCategory::Code
+        );
+        assert_eq!(
+            text.refine_with_path(Path::new("notes")).category,
+            FileCategory::Document
+        );
+
+        let binary = ContentType::detect(&[0, 1, 2, 3]);
+        assert_eq!(binary.mime_type, BINARY_MIME_TYPE);
+        assert_eq!(binary.category, FileCategory::Other);
+    }
+}
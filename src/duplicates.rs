@@ -0,0 +1,209 @@
+use crate::posix_metadata::FileId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Paths of a content, with the identity of the file shared by its hard links
+#[derive(Debug, Default)]
+struct ContentFiles {
+    paths: BTreeMap<PathBuf, Option<FileId>>,
+    // Distinct files among the paths, updated with them
+    copies: usize,
+}
+
+impl ContentFiles {
+    fn wasted_bytes(&self, key: &ContentKey) -> u64 {
+        key.size * (self.copies as u64).saturating_sub(1)
+    }
+}
+
+/// Files with the same size and hash are considered to have the same content
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ContentKey {
+    size: u64,
+    hash: String,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    pub hash: String,
+    pub size: u64,
+    /// Bytes that would be freed by keeping a single copy, hard links of a same file take no space
+    pub wasted_bytes: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct PageRequest {
+    #[serde(default)]
+    pub page: usize,
+    #[serde(default = "default_page_size")]
+    pub per_page: usize,
+}
+
+fn default_page_size() -> usize {
+    DEFAULT_PAGE_SIZE
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DuplicatesPage {
+    pub page: usize,
+    pub per_page: usize,
+    pub total_groups: usize,
+    /// Wasted bytes over every group, not only the ones of the page
+    pub wasted_bytes: u64,
+    pub groups: Vec<DuplicateGroup>,
+}
+
+/// Files of the watched directories grouped by content, kept in sync with the inventory so that
+/// duplicates can be listed locally whether the Hub is reachable or not.
+#[derive(Debug, Default)]
+pub struct DuplicateIndex {
+    groups: HashMap<ContentKey, ContentFiles>,
+    keys: HashMap<PathBuf, ContentKey>,
+}
+
+impl DuplicateIndex {
+    /// Records the content of a file, a file without hash cannot be compared and is left out.
+    pub fn insert(&mut self, path: &Path, size: u64, hash: Option<&str>, file_id: Option<FileId>) {
+        self.remove(path);
+        // Empty files all look alike but waste nothing
+        let hash = match hash {
+            Some(hash) if size > 0 => hash,
+            _ => return,
+        };
+        let key = ContentKey {
+            size,
+            hash: String::from(hash),
+        };
+        let files = self.groups.entry(key.clone()).or_default();
+        files.paths.insert(path.to_path_buf(), file_id);
+        files.copies = copies(&files.paths);
+        self.keys.insert(path.to_path_buf(), key);
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        if let Some(key) = self.keys.remove(path) {
+            if let Some(files) = self.groups.get_mut(&key) {
+                files.paths.remove(path);
+                if files.paths.is_empty() {
+                    self.groups.remove(&key);
+                } else {
+                    files.copies = copies(&files.paths);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.keys.clear();
+    }
+
+    /// Copy of the groups of duplicates, to be sorted and paged once the index is unlocked.
+    pub fn snapshot(&self) -> Vec<DuplicateGroup> {
+        self.groups
+            .iter()
+            .filter(|(_, files)| files.copies > 1)
+            .map(|(key, files)| DuplicateGroup {
+                hash: key.hash.clone(),
+                size: key.size,
+                wasted_bytes: files.wasted_bytes(key),
+                paths: files.paths.keys().cloned().collect(),
+            })
+            .collect()
+    }
+}
+
+/// Groups of duplicates, the ones wasting the most space first.
+pub fn page(mut groups: Vec<DuplicateGroup>, request: PageRequest) -> DuplicatesPage {
+    let per_page = request.per_page.clamp(1, MAX_PAGE_SIZE);
+    groups.sort_unstable_by(|a, b| {
+        b.wasted_bytes
+            .cmp(&a.wasted_bytes)
+            .then_with(|| a.hash.cmp(&b.hash))
+    });
+
+    DuplicatesPage {
+        page: request.page,
+        per_page,
+        total_groups: groups.len(),
+        wasted_bytes: groups.iter().map(|group| group.wasted_bytes).sum(),
+        groups: groups
+            .into_iter()
+            .skip(request.page.saturating_mul(per_page))
+            .take(per_page)
+            .collect(),
+    }
+}
+
+/// Number of distinct files among the paths, hard links being a single file
+fn copies(paths: &BTreeMap<PathBuf, Option<FileId>>) -> usize {
+    let mut file_ids: Vec<FileId> = paths.values().flatten().copied().collect();
+    let unidentified = paths.len() - file_ids.len();
+    file_ids.sort_unstable();
+    file_ids.dedup();
+    unidentified + file_ids.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_files_by_content() {
+        let mut index = DuplicateIndex::default();
+        index.insert(Path::new("/w/a"), 10, Some("aa"), None);
+        index.insert(Path::new("/w/b"), 10, Some("aa"), None);
+        index.insert(Path::new("/w/c"), 10, Some("aa"), None);
+        index.insert(Path::new("/w/d"), 100, Some("dd"), None);
+        index.insert(Path::new("/w/e"), 100, Some("dd"), None);
+        index.insert(Path::new("/w/unique"), 100, Some("ff"), None);
+        index.insert(Path::new("/w/empty"), 0, Some("00"), None);
+        index.insert(Path::new("/w/empty2"), 0, Some("00"), None);
+        // Hard links of a same file are not copies of it
+        let file_id = FileId {
+            device: 1,
+            inode: 7,
+        };
+        index.insert(Path::new("/w/link1"), 1000, Some("11"), Some(file_id));
+        index.insert(Path::new("/w/link2"), 1000, Some("11"), Some(file_id));
+
+        let page = super::page(
+            index.snapshot(),
+            PageRequest {
+                page: 0,
+                per_page: 1,
+            },
+        );
+        assert_eq!((page.total_groups, page.wasted_bytes), (2, 120));
+        assert_eq!(
+            page.groups,
+            vec![DuplicateGroup {
+                hash: String::from("dd"),
+                size: 100,
+                wasted_bytes: 100,
+                paths: vec![PathBuf::from("/w/d"), PathBuf::from("/w/e")],
+            }]
+        );
+
+        // A modified file leaves its group
+        index.insert(Path::new("/w/e"), 100, Some("ee"), None);
+        index.remove(Path::new("/w/c"));
+        let page = super::page(
+            index.snapshot(),
+            PageRequest {
+                page: 0,
+                per_page: DEFAULT_PAGE_SIZE,
+            },
+        );
+        assert_eq!((page.total_groups, page.wasted_bytes), (1, 10));
+        assert_eq!(
+            page.groups[0].paths,
+            vec![PathBuf::from("/w/a"), PathBuf::from("/w/b")]
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use config::ConfigError as config_error;
 use std::io::Error as io_error;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -10,6 +11,22 @@ pub enum AgentError {
     Io(#[from] io_error),
     #[error("Path entry isn't a directory")]
     NotADirectory(),
+    #[error("Invalid TLS configuration: {0}")]
+    InvalidTlsConfig(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidSetting(String),
+}
+
+#[derive(Error, Debug)]
+pub enum FileInfoError {
+    #[error("Could not read {0:?}: {1}")]
+    Io(PathBuf, #[source] io_error),
+    #[error("{0:?} kept changing while being hashed")]
+    ChangedWhileHashing(PathBuf),
+    #[error("Could not list the entries of the archive {0:?}: {1}")]
+    Archive(PathBuf, String),
+    #[error("Not listing the entries of the archive {0:?}: {1}")]
+    ArchiveLimit(PathBuf, String),
 }
 
 #[derive(Error, Debug)]
@@ -38,4 +55,10 @@ pub enum GrpcClientError {
     FileInfoError(),
     #[error("Error sending event to Hub")]
     EventSendError(),
+    #[error("Invalid command from the Hub: {0}")]
+    InvalidCommand(String),
+    #[error("Outbound event queue error: {0}")]
+    EventQueueError(#[from] AgentError),
+    #[error("Scan failed: {0}")]
+    ScanError(AgentError),
 }
@@ -0,0 +1,523 @@
+use crate::configuration::{EventPipelineConfig, OverflowPolicy};
+use notify::event::{EventKind, ModifyKind};
+use notify::Event;
+use notify_debouncer_full::DebouncedEvent;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, warn};
+
+// region: --- Counters
+
+#[derive(Debug, Default)]
+pub struct PipelineCounters {
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+    spilled: AtomicU64,
+    merged_writes: AtomicU64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PipelineMetrics {
+    pub dropped: u64,
+    pub coalesced: u64,
+    pub spilled: u64,
+    /// Events on a file still being written, merged into the event sent once it is complete
+    pub merged_writes: u64,
+}
+
+impl PipelineCounters {
+    pub fn snapshot(&self) -> PipelineMetrics {
+        PipelineMetrics {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            spilled: self.spilled.load(Ordering::Relaxed),
+            merged_writes: self.merged_writes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_merged_write(&self) {
+        self.merged_writes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// endregion: --- Counters
+
+// region: --- Overflow
+
+/// Events that did not fit in the channel, keyed by their paths so that a single event is kept
+/// for the same paths, see `supersedes`.
+#[derive(Default)]
+struct CoalescedEvents {
+    order: VecDeque<Vec<PathBuf>>,
+    events: HashMap<Vec<PathBuf>, DebouncedEvent>,
+}
+
+impl CoalescedEvents {
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Returns true when the event was merged with a pending one.
+    fn push(&mut self, event: DebouncedEvent) -> bool {
+        let key = event.paths.clone();
+        if let Some(pending) = self.events.get_mut(&key) {
+            if supersedes(&event.kind, &pending.kind) {
+                *pending = event;
+            }
+            return true;
+        }
+        self.events.insert(key.clone(), event);
+        self.order.push_back(key);
+        false
+    }
+
+    fn pop(&mut self) -> Option<DebouncedEvent> {
+        let key = self.order.pop_front()?;
+        self.events.remove(&key)
+    }
+}
+
+/// Whether a newer event on the same paths says more than the pending one. A removal, a
+/// creation or a rename tells whether the file is there now; otherwise a creation is not
+/// downgraded to a modification, nor a content change to a metadata change or an access.
+fn supersedes(newer: &EventKind, pending: &EventKind) -> bool {
+    match (newer, pending) {
+        (
+            EventKind::Remove(_) | EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)),
+            _,
+        ) => true,
+        (_, EventKind::Create(_)) => false,
+        (EventKind::Modify(ModifyKind::Data(_)), _) => true,
+        (EventKind::Modify(ModifyKind::Metadata(_)) | EventKind::Access(_), _) => !matches!(
+            pending,
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any)
+        ),
+        _ => true,
+    }
+}
+
+/// Events that did not fit in the channel, appended as JSON lines to a file and read back in order.
+/// The file outlives the agent: the events a previous run spilled are handed out first.
+struct SpilledEvents {
+    path: PathBuf,
+    writer: File,
+    reader: BufReader<File>,
+    pending: usize,
+}
+
+impl SpilledEvents {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let writer = OpenOptions::new().create(true).append(true).open(path)?;
+        let (pending, complete_len) = count_lines(path)?;
+        if complete_len < writer.metadata()?.len() {
+            // The last line was cut by a crash, the next one would be appended to it
+            writer.set_len(complete_len)?;
+        }
+        if pending > 0 {
+            warn!(
+                "Replaying {pending} events spilled to {} by the previous run",
+                path.display()
+            );
+        }
+        let reader = BufReader::new(File::open(path)?);
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+            reader,
+            pending,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending == 0
+    }
+
+    fn push(&mut self, event: &DebouncedEvent) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(&event.event)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Next spilled event, a line that cannot be read back is skipped.
+    fn pop(&mut self) -> Option<DebouncedEvent> {
+        let mut line = Vec::new();
+        while self.pending > 0 {
+            line.clear();
+            let read = self.reader.read_until(b'\n', &mut line);
+            self.pending -= 1;
+            if self.pending == 0 {
+                // Everything was read back, reclaim the disk space
+                let rewind = self
+                    .writer
+                    .set_len(0)
+                    .and_then(|_| self.reader.seek(SeekFrom::Start(0)).map(|_| ()));
+                if let Err(err) = rewind {
+                    error!("Could not truncate {}: {err}", self.path.display());
+                }
+            }
+            if let Err(err) = read {
+                error!(
+                    "Could not read a spilled event from {}: {err}",
+                    self.path.display()
+                );
+                continue;
+            }
+            match serde_json::from_slice::<Event>(&line) {
+                Ok(event) => return Some(DebouncedEvent::new(event, Instant::now())),
+                Err(err) => error!("Skipping a spilled event that cannot be decoded: {err}"),
+            }
+        }
+        None
+    }
+}
+
+/// Number of complete lines of a file, and the length they span.
+fn count_lines(path: &Path) -> std::io::Result<(usize, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut lines, mut complete_len) = (0, 0);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") {
+            return Ok((lines, complete_len));
+        }
+        lines += 1;
+        complete_len += read as u64;
+    }
+}
+
+enum Overflow {
+    Block,
+    Coalesce(CoalescedEvents),
+    Spill(SpilledEvents),
+}
+
+impl Overflow {
+    fn is_empty(&self) -> bool {
+        match self {
+            Overflow::Block => true,
+            Overflow::Coalesce(events) => events.is_empty(),
+            Overflow::Spill(events) => events.is_empty(),
+        }
+    }
+
+    fn pop(&mut self) -> Option<DebouncedEvent> {
+        match self {
+            Overflow::Block => None,
+            Overflow::Coalesce(events) => events.pop(),
+            Overflow::Spill(events) => events.pop(),
+        }
+    }
+}
+
+// endregion: --- Overflow
+
+#[derive(Debug)]
+pub struct PipelineClosed;
+
+/// Sending half of the bounded pipeline between the file watcher thread and the gRPC client.
+pub struct EventSender {
+    sender: mpsc::Sender<DebouncedEvent>,
+    overflow: Arc<Mutex<Overflow>>,
+    counters: Arc<PipelineCounters>,
+}
+
+/// Receiving half of the pipeline, yields the events in the order they were sent: the channel
+/// first, then the overflow which only fills once the channel is full.
+pub struct EventReceiver {
+    receiver: mpsc::Receiver<DebouncedEvent>,
+    overflow: Arc<Mutex<Overflow>>,
+}
+
+pub fn channel(
+    config: &EventPipelineConfig,
+    spill_path: &Path,
+    counters: Arc<PipelineCounters>,
+) -> (EventSender, EventReceiver) {
+    let (sender, receiver) = mpsc::channel(config.capacity.max(1));
+    let overflow = match config.overflow_policy {
+        OverflowPolicy::Block => Overflow::Block,
+        OverflowPolicy::Coalesce => Overflow::Coalesce(CoalescedEvents::default()),
+        OverflowPolicy::Spill => match SpilledEvents::open(spill_path) {
+            Ok(spilled) => Overflow::Spill(spilled),
+            Err(err) => {
+                error!(
+                    "Could not open the spill file {}: {err}, blocking on overflow instead",
+                    spill_path.display()
+                );
+                Overflow::Block
+            }
+        },
+    };
+    let overflow = Arc::new(Mutex::new(overflow));
+
+    (
+        EventSender {
+            sender,
+            overflow: overflow.clone(),
+            counters,
+        },
+        EventReceiver { receiver, overflow },
+    )
+}
+
+impl EventSender {
+    /// Sends an event from a synchronous thread, applying the overflow policy when the pipeline
+    /// is full. Fails once the receiver is gone.
+    pub fn send(&self, event: DebouncedEvent) -> Result<(), PipelineClosed> {
+        let mut overflow = self.overflow.lock().unwrap();
+
+        // Once events overflowed, the next ones follow them so that the order is kept
+        let event = if overflow.is_empty() {
+            match self.sender.try_send(event) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(_)) => {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Err(PipelineClosed);
+                }
+                Err(TrySendError::Full(event)) => event,
+            }
+        } else if self.sender.is_closed() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(PipelineClosed);
+        } else {
+            event
+        };
+
+        match &mut *overflow {
+            Overflow::Block => {
+                drop(overflow);
+                self.sender.blocking_send(event).map_err(|_| {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    PipelineClosed
+                })
+            }
+            Overflow::Coalesce(events) => {
+                if events.push(event) {
+                    self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+            Overflow::Spill(events) => {
+                match events.push(&event) {
+                    Ok(()) => self.counters.spilled.fetch_add(1, Ordering::Relaxed),
+                    Err(err) => {
+                        warn!("Could not spill event to disk: {err}");
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+                Ok(())
+            }
+        }
+    }
+}
+
+impl EventReceiver {
+    pub async fn recv(&mut self) -> Option<DebouncedEvent> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) => return Some(event),
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    // The overflow is handed out until it is empty, None only then
+                    return self.overflow.lock().unwrap().pop();
+                }
+                Err(mpsc::error::TryRecvError::Empty) => (),
+            }
+            if let Some(event) = self.overflow.lock().unwrap().pop() {
+                return Some(event);
+            }
+            match self.receiver.recv().await {
+                Some(event) => return Some(event),
+                None => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, MetadataKind, RemoveKind};
+
+    fn event(kind: EventKind, path: &str) -> DebouncedEvent {
+        DebouncedEvent::new(
+            Event::new(kind).add_path(PathBuf::from(path)),
+            Instant::now(),
+        )
+    }
+
+    fn config(capacity: usize, overflow_policy: OverflowPolicy) -> EventPipelineConfig {
+        EventPipelineConfig {
+            capacity,
+            overflow_policy,
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesces_events_on_the_same_path() {
+        let counters = Arc::new(PipelineCounters::default());
+        let (sender, mut receiver) = channel(
+            &config(1, OverflowPolicy::Coalesce),
+            Path::new(""),
+            counters.clone(),
+        );
+
+        sender
+            .send(event(EventKind::Create(CreateKind::File), "/a"))
+            .unwrap();
+        sender
+            .send(event(EventKind::Create(CreateKind::File), "/b"))
+            .unwrap();
+        sender
+            .send(event(EventKind::Create(CreateKind::File), "/c"))
+            .unwrap();
+        sender
+            .send(event(EventKind::Modify(ModifyKind::Any), "/b"))
+            .unwrap();
+        drop(sender);
+
+        let mut received = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            received.push((event.kind, event.paths[0].clone()));
+        }
+        assert_eq!(
+            received,
+            vec![
+                (EventKind::Create(CreateKind::File), PathBuf::from("/a")),
+                (EventKind::Create(CreateKind::File), PathBuf::from("/b")),
+                (EventKind::Create(CreateKind::File), PathBuf::from("/c")),
+            ]
+        );
+        assert_eq!(counters.snapshot().coalesced, 1);
+    }
+
+    #[test]
+    fn coalescing_keeps_the_most_significant_event() {
+        let merged = |kinds: &[EventKind]| {
+            let mut events = CoalescedEvents::default();
+            for kind in kinds {
+                events.push(event(*kind, "/a"));
+            }
+            events.pop().unwrap().kind
+        };
+        let create = EventKind::Create(CreateKind::File);
+        let data = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        let metadata = EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any));
+        let remove = EventKind::Remove(RemoveKind::File);
+
+        assert_eq!(merged(&[create, metadata]), create);
+        assert_eq!(merged(&[create, data]), create);
+        assert_eq!(merged(&[data, metadata]), data);
+        assert_eq!(merged(&[metadata, data]), data);
+        assert_eq!(merged(&[create, data, remove]), remove);
+        // A file created again after its removal exists
+        assert_eq!(merged(&[remove, create]), create);
+    }
+
+    #[tokio::test]
+    async fn spills_events_to_disk_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let counters = Arc::new(PipelineCounters::default());
+        let (sender, mut receiver) = channel(
+            &config(2, OverflowPolicy::Spill),
+            &dir.path().join("spill.jsonl"),
+            counters.clone(),
+        );
+
+        for i in 0..10 {
+            sender
+                .send(event(EventKind::Create(CreateKind::File), &format!("/{i}")))
+                .unwrap();
+        }
+        drop(sender);
+
+        let mut received = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            received.push(event.paths[0].clone());
+        }
+        assert_eq!(
+            received,
+            (0..10)
+                .map(|i| PathBuf::from(format!("/{i}")))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(counters.snapshot().spilled, 8);
+        assert_eq!(
+            fs::metadata(dir.path().join("spill.jsonl")).unwrap().len(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn replays_the_events_spilled_by_a_previous_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill_path = dir.path().join("spill.jsonl");
+        let spilled = |path: &str| {
+            serde_json::to_string(&event(EventKind::Create(CreateKind::File), path).event).unwrap()
+        };
+        fs::write(
+            &spill_path,
+            format!(
+                "{}\nnot an event\n{}\n{{\"cut",
+                spilled("/old-1"),
+                spilled("/old-2")
+            ),
+        )
+        .unwrap();
+        let counters = Arc::new(PipelineCounters::default());
+        let (sender, mut receiver) = channel(
+            &config(2, OverflowPolicy::Spill),
+            &spill_path,
+            counters.clone(),
+        );
+
+        // Sent after the replayed events, whatever room is left in the channel
+        sender
+            .send(event(EventKind::Create(CreateKind::File), "/new"))
+            .unwrap();
+        drop(sender);
+
+        let mut received = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            received.push(event.paths[0].clone());
+        }
+        assert_eq!(
+            received,
+            vec![
+                PathBuf::from("/old-1"),
+                PathBuf::from("/old-2"),
+                PathBuf::from("/new")
+            ]
+        );
+        assert_eq!(fs::metadata(&spill_path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn fails_once_the_receiver_is_dropped() {
+        let counters = Arc::new(PipelineCounters::default());
+        let (sender, receiver) = channel(
+            &config(1, OverflowPolicy::Block),
+            Path::new(""),
+            counters.clone(),
+        );
+        drop(receiver);
+
+        assert!(sender
+            .send(event(EventKind::Create(CreateKind::File), "/a"))
+            .is_err());
+        assert_eq!(counters.snapshot().dropped, 1);
+    }
+}
@@ -1,32 +1,96 @@
+use crate::configuration::HashAlgorithm;
+use crate::content_type::{ContentType, FileCategory, CONTENT_TYPE_HEAD_LEN};
+use crate::error::FileInfoError;
+use crate::hash_cache::HashCache;
+use crate::media_metadata::{self, MediaMetadata};
+use crate::posix_metadata::PosixMetadata;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     fs,
-    io::Read,
+    io::{ErrorKind, Read},
     path::{Path, PathBuf},
     time::SystemTime,
 };
-use tracing::warn;
-use xxhash_rust::xxh3::xxh3_128;
+use tracing::{debug, warn};
+use xxhash_rust::xxh3::Xxh3;
+
+// Files are streamed through the hasher so that memory use does not depend on their size
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+// A file still being written to is hashed again, up to this many times
+const HASH_ATTEMPTS: usize = 3;
+
+// Shared MIME type of the symbolic links
+const SYMLINK_MIME_TYPE: &str = "inode/symlink";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    #[default]
+    Regular,
+    /// A symbolic link reported as itself instead of the file it points to
+    Symlink,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileInfo {
-    pub pretty_path: PathBuf,
+    /// Canonical path, see `WatchedRoots` for the path relative to the watched directory
     pub path: PathBuf,
+    /// Path inside the archive at `path`, for the entries of an archive
+    pub inner_path: Option<PathBuf>,
+    pub kind: FileKind,
+    /// Target of a symbolic link, as stored in the link
+    pub symlink_target: Option<PathBuf>,
     pub size: u64,
     pub hash: Option<String>,
+    pub hash_algorithm: HashAlgorithm,
+    pub mime_type: Option<String>,
+    pub category: FileCategory,
     pub last_modified: SystemTime,
     pub last_accessed: SystemTime,
+    /// Birth time, when the platform and the filesystem record it
+    pub created: Option<SystemTime>,
+    pub posix: Option<PosixMetadata>,
+    pub media: Option<MediaMetadata>,
+}
+
+/// What is learnt by reading a file: its hash, its type from its first bytes and, for photos and
+/// media, their embedded metadata
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FileContent {
+    pub hash: String,
+    pub content_type: ContentType,
+    pub media: Option<MediaMetadata>,
 }
 
 impl Default for FileInfo {
     fn default() -> Self {
         FileInfo {
-            pretty_path: PathBuf::new(),
             path: PathBuf::new(),
+            inner_path: None,
+            kind: FileKind::Regular,
+            symlink_target: None,
             size: 0,
             hash: None,
+            hash_algorithm: HashAlgorithm::default(),
+            mime_type: None,
+            category: FileCategory::default(),
             last_modified: SystemTime::UNIX_EPOCH,
             last_accessed: SystemTime::UNIX_EPOCH,
+            created: None,
+            posix: None,
+            media: None,
+        }
+    }
+}
+
+impl FileInfo {
+    /// Identifies the file in the inventory: its path, followed by the path inside the archive
+    /// for the entries of an archive
+    pub fn full_path(&self) -> PathBuf {
+        match &self.inner_path {
+            Some(inner_path) => self.path.join(inner_path),
+            None => self.path.clone(),
         }
     }
 }
@@ -53,84 +117,278 @@ pub fn fix_canonicalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
     }
 }
 
-pub fn get_file_signature(path: &PathBuf) -> u128 {
-    let mut file = fs::File::open(path).unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    xxh3_128(&buffer)
+enum Hasher {
+    Xxh3_64(Box<Xxh3>),
+    Xxh3_128(Box<Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
 }
 
-pub fn create_file_info(path: &PathBuf) -> Option<FileInfo> {
-    if path.is_dir() {
-        return None;
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Xxh3_64 => Hasher::Xxh3_64(Box::default()),
+            HashAlgorithm::Xxh3_128 => Hasher::Xxh3_128(Box::default()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Xxh3_64(hasher) | Hasher::Xxh3_128(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
     }
 
-    match fs::metadata(path) {
-        Ok(md) => {
-            let size: u64 = md.len();
-            let last_modified: SystemTime = md.modified().ok()?;
-            let last_accessed: SystemTime = md.accessed().ok()?;
-            let file_signature = get_file_signature(path);
-
-            Some(FileInfo {
-                pretty_path: fix_canonicalize_path(fs::canonicalize(path).unwrap()),
-                path: fix_canonicalize_path(fs::canonicalize(path).unwrap()),
-                size,
-                hash: Some(file_signature.to_string()),
-                last_modified,
-                last_accessed,
-                ..Default::default()
-            })
+    /// Lowercase hex encoding of the digest, big endian for the xxh3 integers
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Xxh3_64(hasher) => format!("{:016x}", hasher.digest()),
+            Hasher::Xxh3_128(hasher) => format!("{:032x}", hasher.digest128()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
         }
-        Err(err) => {
-            warn!("Could not get access to {:?} metadata: {}", path, err);
-            None
+    }
+}
+
+/// Size and modification time of the file, used to detect a change while it is hashed
+fn file_version(md: &fs::Metadata) -> (u64, Option<SystemTime>) {
+    (md.len(), md.modified().ok())
+}
+
+/// Hash of a stream with the first bytes needed to recognize its type
+pub struct StreamDigest {
+    pub hash: String,
+    pub head: Vec<u8>,
+    pub len: u64,
+}
+
+/// Streams `reader` through the hasher in chunks, so that memory use does not depend on its size.
+pub fn digest_stream(
+    reader: &mut dyn Read,
+    algorithm: HashAlgorithm,
+) -> std::io::Result<StreamDigest> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; HASH_CHUNK_SIZE];
+    let mut head = Vec::with_capacity(CONTENT_TYPE_HEAD_LEN);
+    let mut len: u64 = 0;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+                if head.len() < CONTENT_TYPE_HEAD_LEN {
+                    let missing = (CONTENT_TYPE_HEAD_LEN - head.len()).min(n);
+                    head.extend_from_slice(&buffer[..missing]);
+                }
+                len += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
     }
+    Ok(StreamDigest {
+        hash: hasher.finalize(),
+        head,
+        len,
+    })
+}
+
+fn read_once(path: &Path, algorithm: HashAlgorithm) -> Result<Option<FileContent>, FileInfoError> {
+    let io_error = |e| FileInfoError::Io(path.to_path_buf(), e);
+    let mut file = fs::File::open(path).map_err(io_error)?;
+    let before = file_version(&file.metadata().map_err(io_error)?);
+    let digest = digest_stream(&mut file, algorithm).map_err(io_error)?;
+
+    // The open handle outlives a removal or a replacement of the file, so the path is checked too
+    let after = file_version(&fs::metadata(path).map_err(io_error)?);
+    if before != after || digest.len != before.0 {
+        return Ok(None);
+    }
+    let content_type = ContentType::detect(&digest.head);
+    Ok(Some(FileContent {
+        hash: digest.hash,
+        media: media_metadata::extract(path, content_type.category),
+        content_type,
+    }))
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-
-//     #[test]
-//     #[cfg(not(target_os = "windows"))]
-//     fn test_fix_canonicalize_path_unix() {
-//         let path = "tests/assets/test_folder";
-//         let canonicalized = fix_canonicalize_path(path);
-//         assert_eq!(canonicalized, PathBuf::from(path));
-//     }
-
-//     #[test]
-//     #[cfg(target_os = "windows")]
-//     fn test_fix_canonicalize_path_windows() {
-//         let path = r"C:\tests\assets\test_folder";
-//         let canonicalized = fix_canonicalize_path(path);
-//         assert_eq!(canonicalized, PathBuf::from(path));
-//     }
-
-//     #[test]
-//     fn test_get_file_signature() {
-//         let path: PathBuf = [r"tests", r"assets", r"test_folder", r"test-file-1"]
-//             .iter()
-//             .collect();
-//         let hash = get_file_signature(&path);
-//         assert_eq!(hash, 53180848542178601830765469314885156230);
-//     }
-
-//     #[test]
-//     fn test_create_file_info() {
-//         let path: PathBuf = [r"tests", r"assets", r"test_folder", r"test-file-1"]
-//             .iter()
-//             .collect();
-//         if let Some(file_info) = create_file_info(&path) {
-//             assert_ne!(file_info.path, path);
-//             assert_eq!(file_info.size, 100);
-//             if let Some(hash) = file_info.hash {
-//                 assert_eq!(hash, "53180848542178601830765469314885156230");
-//             }
-//             assert_ne!(file_info.last_modified, SystemTime::UNIX_EPOCH);
-//             assert_ne!(file_info.last_accessed, SystemTime::UNIX_EPOCH);
-//         }
-//     }
-// }
+/// Canonical path of the file itself: its directory is resolved, but not the file when it is a
+/// symbolic link
+pub fn canonical_location(path: &Path) -> std::io::Result<PathBuf> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+    let location = match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => fs::canonicalize(parent)?.join(name),
+        _ => fs::canonicalize(&absolute)?,
+    };
+    Ok(fix_canonicalize_path(location))
+}
+
+/// Describes a symbolic link without reading what it points to
+fn symlink_info(path: &Path, md: &fs::Metadata) -> Result<FileInfo, FileInfoError> {
+    let io_error = |e| FileInfoError::Io(path.to_path_buf(), e);
+    Ok(FileInfo {
+        path: canonical_location(path).map_err(io_error)?,
+        kind: FileKind::Symlink,
+        symlink_target: fs::read_link(path).ok(),
+        size: md.len(),
+        mime_type: Some(String::from(SYMLINK_MIME_TYPE)),
+        last_modified: md.modified().map_err(io_error)?,
+        last_accessed: md.accessed().map_err(io_error)?,
+        created: md.created().ok(),
+        posix: PosixMetadata::new(md),
+        ..Default::default()
+    })
+}
+
+/// Streams the file through the hasher, recognizing its type from the first chunk. Fails when
+/// the file cannot be read, vanished or kept changing while it was being hashed.
+pub fn read_file_content(
+    path: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<FileContent, FileInfoError> {
+    for _ in 0..HASH_ATTEMPTS {
+        if let Some(content) = read_once(path, algorithm)? {
+            return Ok(content);
+        }
+    }
+    Err(FileInfoError::ChangedWhileHashing(path.to_path_buf()))
+}
+
+/// Describes a regular file, or a symbolic link as itself unless `follow_symlinks` is set.
+/// Directories, FIFOs, sockets and device nodes have no description, reading them could block.
+pub fn describe_file(
+    path: &Path,
+    hash_cache: &HashCache,
+    follow_symlinks: bool,
+) -> Result<Option<FileInfo>, FileInfoError> {
+    let io_error = |e| FileInfoError::Io(path.to_path_buf(), e);
+    let md = match fs::symlink_metadata(path).map_err(io_error)? {
+        md if md.is_symlink() && !follow_symlinks => return symlink_info(path, &md).map(Some),
+        md if md.is_symlink() => fs::metadata(path).map_err(io_error)?,
+        md => md,
+    };
+    if md.is_dir() {
+        return Ok(None);
+    }
+    if !md.is_file() {
+        debug!("Skipping the special file {}", path.display());
+        return Ok(None);
+    }
+
+    let last_modified: SystemTime = md.modified().map_err(io_error)?;
+    let last_accessed: SystemTime = md.accessed().map_err(io_error)?;
+    let content = hash_cache.file_content(path, &md)?;
+    let content_type = content.content_type.refine_with_path(path);
+    Ok(Some(FileInfo {
+        path: canonical_location(path).map_err(io_error)?,
+        inner_path: None,
+        kind: FileKind::Regular,
+        symlink_target: None,
+        size: md.len(),
+        hash: Some(content.hash),
+        hash_algorithm: hash_cache.algorithm(),
+        mime_type: Some(content_type.mime_type),
+        category: content_type.category,
+        last_modified,
+        last_accessed,
+        created: md.created().ok(),
+        posix: PosixMetadata::new(&md),
+        media: content.media,
+    }))
+}
+
+/// Same as `describe_file`, logging why a file could not be described.
+pub fn create_file_info(
+    path: &Path,
+    hash_cache: &HashCache,
+    follow_symlinks: bool,
+) -> Option<FileInfo> {
+    describe_file(path, hash_cache, follow_symlinks).unwrap_or_else(|err| {
+        warn!("{err}");
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xxhash_rust::xxh3::{xxh3_128, xxh3_64};
+
+    #[test]
+    fn hashes_large_files_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large");
+        let content: Vec<u8> = (0..HASH_CHUNK_SIZE * 3 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(&path, &content).unwrap();
+
+        let expected = format!("{:032x}", xxh3_128(&content));
+        assert_eq!(
+            read_file_content(&path, HashAlgorithm::Xxh3_128)
+                .unwrap()
+                .hash,
+            expected
+        );
+        let hash_cache =
+            HashCache::load(&dir.path().join("hash_cache.json"), HashAlgorithm::Xxh3_128);
+        let file_info = create_file_info(&path, &hash_cache, false).unwrap();
+        assert_eq!(file_info.size, content.len() as u64);
+        assert_eq!(file_info.hash, Some(expected));
+        assert_eq!(
+            file_info.mime_type.as_deref(),
+            Some("application/octet-stream")
+        );
+    }
+
+    #[test]
+    fn hex_encodes_every_algorithm() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc");
+        fs::write(&path, "abc").unwrap();
+
+        assert_eq!(
+            read_file_content(&path, HashAlgorithm::Xxh3_64)
+                .unwrap()
+                .hash,
+            format!("{:016x}", xxh3_64(b"abc"))
+        );
+        assert_eq!(
+            read_file_content(&path, HashAlgorithm::Blake3)
+                .unwrap()
+                .hash,
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(
+            read_file_content(&path, HashAlgorithm::Sha256)
+                .unwrap()
+                .hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing");
+
+        assert!(matches!(
+            read_file_content(&path, HashAlgorithm::default()),
+            Err(FileInfoError::Io(_, _))
+        ));
+        let hash_cache = HashCache::load(
+            &dir.path().join("hash_cache.json"),
+            HashAlgorithm::default(),
+        );
+        assert!(create_file_info(&path, &hash_cache, false).is_none());
+    }
+}
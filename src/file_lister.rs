@@ -1,76 +1,742 @@
-use std::fs::read_dir;
-use std::fs::DirEntry;
-use std::path::PathBuf;
-use tracing::info;
-
-use crate::error::AgentError;
-use crate::file_info::{create_file_info, FileInfo};
-
-pub fn list_directories(directories: Vec<PathBuf>) -> Result<Vec<FileInfo>, AgentError> {
-    let mut file_info_vec: Vec<FileInfo> = Vec::new();
-
-    for directory in directories {
-        if directory.is_dir() {
-            for dir_entry in read_dir(&directory)? {
-                let dir_entry: DirEntry = dir_entry?;
-                let dir_path: PathBuf = dir_entry.path();
-
-                if dir_path.is_dir() {
-                    file_info_vec.extend(list_directories(vec![dir_path])?);
-                } else if dir_path.to_str().is_some() {
-                    if let Some(file_info) = create_file_info(&dir_path) {
-                        info!("Found file {}", file_info.path.display());
-                        file_info_vec.push(file_info);
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::{self, read_dir};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::archive;
+use crate::configuration::{ArchiveLimits, FileSystemInterfaceConfig};
+use crate::error::{AgentError, FileInfoError};
+use crate::file_info::{describe_file, fix_canonicalize_path, FileInfo};
+use crate::hash_cache::HashCache;
+use crate::ignore_rules::IgnoreRules;
+use crate::posix_metadata::FileId;
+use crate::scan_report::ScanReport;
+
+/// Files found by the walk before they are described together on the scan thread pool, the scan
+/// itself only holds the directories being walked and the files of a batch
+const SCAN_BATCH_SIZE: usize = 256;
+/// Batches described ahead of their consumer
+const SCAN_QUEUED_BATCHES: usize = 4;
+
+/// How the files of the watched directories are discovered and described
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    pub archive_introspection: bool,
+    pub archive_limits: ArchiveLimits,
+    pub follow_symlinks: bool,
+    /// Shared with the watcher, which reloads them when an ignore file changes
+    pub ignore_rules: Arc<IgnoreRules>,
+}
+
+impl ScanOptions {
+    pub fn new(config: &FileSystemInterfaceConfig) -> Self {
+        Self {
+            archive_introspection: config.archive_introspection,
+            archive_limits: config.archive_limits,
+            follow_symlinks: config.follow_symlinks,
+            ignore_rules: Arc::new(IgnoreRules::new(config)),
+        }
+    }
+
+    /// Whether the scan enters `path`: a link to a directory is only entered when followed
+    pub fn is_scanned_directory(&self, path: &Path) -> bool {
+        if self.follow_symlinks {
+            path.is_dir()
+        } else {
+            fs::symlink_metadata(path).is_ok_and(|md| md.is_dir())
+        }
+    }
+
+    /// Whether the scan reaches `path`, found in `root`: the directories leading to it are
+    /// links only when links are followed
+    pub fn reaches(&self, path: &Path, root: &Path) -> bool {
+        self.follow_symlinks
+            || path
+                .ancestors()
+                .skip(1)
+                .take_while(|ancestor| *ancestor != root && ancestor.starts_with(root))
+                .all(|ancestor| !fs::symlink_metadata(ancestor).is_ok_and(|md| md.is_symlink()))
+    }
+}
+
+/// Identity of a directory, to visit it once whatever the links leading to it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum DirectoryKey {
+    File(FileId),
+    // Where files have no device and inode
+    Path(PathBuf),
+}
+
+impl DirectoryKey {
+    fn new(path: &Path, md: &fs::Metadata) -> Option<Self> {
+        match FileId::new(md) {
+            Some(file_id) => Some(Self::File(file_id)),
+            None => path.canonicalize().ok().map(Self::Path),
+        }
+    }
+}
+
+/// A file found by the walk, with its identity when another path may lead to the same file: it
+/// has hard links, or it was found through a followed link
+struct FoundFile {
+    path: PathBuf,
+    file_id: Option<FileId>,
+}
+
+/// Sizes the worker pool used to hash the files, 0 uses one thread per core.
+/// The pool can only be configured once per process.
+pub fn configure_scan_threads(threads: usize) {
+    if let Err(err) = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("scanner-{index}"))
+        .build_global()
+    {
+        warn!("Could not configure the scan thread pool: {err}");
+    }
+}
+
+/// A watched directory to scan, with the identity of the directory itself
+struct ScanRoot {
+    path: PathBuf,
+    key: Option<DirectoryKey>,
+}
+
+fn scan_root(directory: &Path) -> Result<ScanRoot, AgentError> {
+    if !directory.is_dir() {
+        return Err(AgentError::NotADirectory());
+    }
+    // Ignore rules are matched against the canonical paths of the watched directories
+    let path = fix_canonicalize_path(directory.canonicalize()?);
+    let key = DirectoryKey::new(&path, &fs::metadata(&path)?);
+    Ok(ScanRoot { path, key })
+}
+
+/// Checks and canonicalizes the watched directories, a missing or unreadable one is skipped so
+/// that the others are still scanned. They are sorted so that the files come in path order.
+fn scan_roots(directories: &[PathBuf], report: &mut ScanReport) -> Vec<ScanRoot> {
+    let mut roots: Vec<ScanRoot> = directories
+        .iter()
+        .filter_map(|directory| match scan_root(directory) {
+            Ok(root) => Some(root),
+            Err(err) => {
+                report.record_skipped_root(directory, &err);
+                None
+            }
+        })
+        .collect();
+    roots.sort_by(|a, b| a.path.cmp(&b.path));
+    roots
+}
+
+/// Walks the watched directories depth first, the entries of each directory sorted so that the
+/// files come in path order. Only the directories being walked are listed in memory, along with
+/// the identity of the watched directories and of the directories reached through a link.
+///
+/// Symbolic links are found as files unless they are followed. A followed link to a directory
+/// being walked, or already reached through another link, is skipped so that link loops end.
+/// Ignored files and directories are left out, the ignore file of each directory being read on
+/// the way.
+struct Walker {
+    options: ScanOptions,
+    roots: std::vec::IntoIter<ScanRoot>,
+    // Only links can lead to a directory twice, the other directories are not kept
+    visited: HashSet<DirectoryKey>,
+    // Entries left in the directories being walked, the innermost last, with the identity of the
+    // directory when links are followed
+    stack: Vec<(Option<DirectoryKey>, std::vec::IntoIter<PathBuf>)>,
+}
+
+impl Walker {
+    fn new(roots: Vec<ScanRoot>, options: ScanOptions) -> Self {
+        Self {
+            options,
+            roots: roots.into_iter(),
+            visited: HashSet::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    fn enter(&mut self, directory: &Path, key: Option<DirectoryKey>, report: &mut ScanReport) {
+        let mut dir_paths = Vec::new();
+        let entries = match read_dir(directory) {
+            Ok(entries) => entries,
+            Err(err) => {
+                report.record_skipped_directory(directory, &err);
+                return;
+            }
+        };
+        for dir_entry in entries {
+            match dir_entry {
+                Ok(dir_entry) => dir_paths.push(dir_entry.path()),
+                Err(err) => report.record_io_error(directory, &err),
+            }
+        }
+        dir_paths.sort();
+        report.directories_scanned += 1;
+        self.options.ignore_rules.load_directory(directory);
+        self.stack.push((key, dir_paths.into_iter()));
+    }
+
+    /// Next file of the walk, None once every watched directory was walked
+    fn next_file(&mut self, report: &mut ScanReport) -> Option<FoundFile> {
+        loop {
+            let dir_path = match self.stack.last_mut() {
+                Some((_, entries)) => match entries.next() {
+                    Some(dir_path) => dir_path,
+                    None => {
+                        self.stack.pop();
+                        continue;
+                    }
+                },
+                None => {
+                    let root = self.roots.next()?;
+                    if let Some(key) = &root.key {
+                        self.visited.insert(key.clone());
                     }
+                    self.enter(&root.path, root.key, report);
+                    continue;
+                }
+            };
+
+            let mut md = match fs::symlink_metadata(&dir_path) {
+                Ok(md) => md,
+                Err(err) => {
+                    report.record_io_error(&dir_path, &err);
+                    continue;
                 }
+            };
+            if self
+                .options
+                .ignore_rules
+                .is_ignored_entry(&dir_path, md.is_dir())
+            {
+                debug!("Ignoring {}", dir_path.display());
+                continue;
             }
-        } else {
-            return Err(AgentError::NotADirectory());
+            let reported_as_link = md.is_symlink() && !self.options.follow_symlinks;
+            let followed_link = md.is_symlink() && self.options.follow_symlinks;
+            if followed_link {
+                md = match fs::metadata(&dir_path) {
+                    Ok(md) => md,
+                    Err(err) => {
+                        warn!("Skipping the broken link {}: {err}", dir_path.display());
+                        continue;
+                    }
+                };
+            }
+
+            if reported_as_link {
+                return Some(FoundFile {
+                    path: dir_path,
+                    file_id: None,
+                });
+            } else if md.is_dir() {
+                let key = if self.options.follow_symlinks {
+                    DirectoryKey::new(&dir_path, &md)
+                } else {
+                    None
+                };
+                let first_visit = match &key {
+                    Some(key) if followed_link => {
+                        !self
+                            .stack
+                            .iter()
+                            .any(|(walked, _)| walked.as_ref() == Some(key))
+                            && self.visited.insert(key.clone())
+                    }
+                    _ => true,
+                };
+                if first_visit {
+                    self.enter(&dir_path, key, report);
+                } else {
+                    warn!(
+                        "Skipping {}, the directory was already scanned through another link",
+                        dir_path.display()
+                    );
+                }
+            } else if !md.is_file() {
+                debug!("Skipping the special file {}", dir_path.display());
+            } else if dir_path.to_str().is_some() {
+                return Some(FoundFile {
+                    file_id: shared_file_id(&md, followed_link),
+                    path: dir_path,
+                });
+            }
+        }
+    }
+}
+
+/// Identity of a file other paths may lead to, None for a file with a single path
+fn shared_file_id(md: &fs::Metadata, followed_link: bool) -> Option<FileId> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if !followed_link && md.nlink() < 2 {
+            return None;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = followed_link;
+    FileId::new(md)
+}
+
+/// Scan of the watched directories, described batch by batch: the walk finds up to
+/// `SCAN_BATCH_SIZE` files, which are hashed on the scan thread pool. The files come in walk
+/// order whatever the order in which they were hashed, the entries of an archive right after
+/// it when archive introspection is enabled.
+///
+/// The directories and files that cannot be read are skipped and listed in the report.
+struct Scanner<'a> {
+    walker: Walker,
+    hash_cache: &'a HashCache,
+    report: ScanReport,
+    done: bool,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(directories: &[PathBuf], hash_cache: &'a HashCache, options: ScanOptions) -> Self {
+        let mut report = ScanReport::new(directories);
+        Self {
+            walker: Walker::new(scan_roots(directories, &mut report), options),
+            hash_cache,
+            report,
+            done: false,
+        }
+    }
+
+    /// Next files of the scan, None once the scan is over
+    fn next_batch(&mut self) -> Option<Vec<FileInfo>> {
+        if self.done {
+            return None;
         }
+        let mut found = Vec::with_capacity(SCAN_BATCH_SIZE);
+        while found.len() < SCAN_BATCH_SIZE {
+            match self.walker.next_file(&mut self.report) {
+                Some(file) => found.push(file),
+                None => break,
+            }
+        }
+        if found.is_empty() {
+            self.done = true;
+            self.report.finish();
+            return None;
+        }
+
+        // The paths of a same file are hard links or links to it: the file is read through its
+        // first path, then its other paths are described from the hash cache
+        let mut first_paths = HashSet::new();
+        let is_first: Vec<bool> = found
+            .iter()
+            .map(|file| {
+                file.file_id
+                    .map_or(true, |file_id| first_paths.insert(file_id))
+            })
+            .collect();
+        let options = &self.walker.options;
+        let hash_cache = self.hash_cache;
+        let describe = |(file, is_first): (&FoundFile, &bool), pass: bool| {
+            if *is_first != pass {
+                return None;
+            }
+            let file_info = describe_file(&file.path, hash_cache, options.follow_symlinks);
+            if let Ok(Some(file_info)) = &file_info {
+                info!("Found file {}", file_info.path.display());
+            }
+            Some(file_info)
+        };
+        let mut described: Vec<Option<Result<Option<FileInfo>, FileInfoError>>> = found
+            .par_iter()
+            .zip(&is_first)
+            .map(|file| describe(file, true))
+            .collect();
+        if is_first.contains(&false) {
+            let other_paths: Vec<_> = found
+                .par_iter()
+                .zip(&is_first)
+                .map(|file| describe(file, false))
+                .collect();
+            for (result, other_path) in described.iter_mut().zip(other_paths) {
+                if other_path.is_some() {
+                    *result = other_path;
+                }
+            }
+        }
+
+        let mut files = Vec::with_capacity(found.len());
+        for result in described.into_iter().flatten() {
+            match result {
+                Ok(file_info) => files.extend(file_info),
+                Err(err) => self.report.record_skipped_file(&err),
+            }
+        }
+
+        let with_entries: Vec<(FileInfo, Result<Vec<FileInfo>, FileInfoError>)> = files
+            .into_par_iter()
+            .map(|file_info| {
+                let entries = if options.archive_introspection {
+                    archive::list_entries(&file_info, hash_cache, &options.archive_limits)
+                } else {
+                    Ok(Vec::new())
+                };
+                (file_info, entries)
+            })
+            .collect();
+        let mut files = Vec::with_capacity(with_entries.len());
+        for (file_info, entries) in with_entries {
+            files.push(file_info);
+            match entries {
+                Ok(entries) => files.extend(entries),
+                Err(err) => self.report.record_file_error(&err),
+            }
+        }
+        self.report.files_found += files.len();
+        Some(files)
+    }
+}
+
+/// Scan running on the blocking pool, whose files are received batch by batch as they are
+/// described. The scan holds a few batches at a time and waits for the consumer when it falls
+/// behind; what the consumer keeps of the files is up to it.
+pub struct FileScan {
+    batches: mpsc::Receiver<Vec<FileInfo>>,
+    scan: JoinHandle<Result<ScanReport, AgentError>>,
+    cache_generation: u64,
+}
+
+impl FileScan {
+    /// Next files of the scan, in walk order, None once the scan is over
+    pub async fn next_batch(&mut self) -> Option<Vec<FileInfo>> {
+        self.batches.recv().await
+    }
+
+    /// Hash cache generation started by the scan, see `HashCache::prune`.
+    #[inline]
+    pub fn cache_generation(&self) -> u64 {
+        self.cache_generation
     }
 
-    Ok(file_info_vec)
+    /// Waits for the end of the scan. A scan dropped before its last batch stops early.
+    pub async fn finish(self) -> Result<ScanReport, AgentError> {
+        drop(self.batches);
+        match self.scan.await {
+            Ok(result) => result,
+            Err(e) => Err(AgentError::Io(std::io::Error::other(e))),
+        }
+    }
+}
+
+/// Starts scanning the directories, see `FileScan`.
+pub fn stream_directories(
+    directories: Vec<PathBuf>,
+    hash_cache: Arc<HashCache>,
+    options: ScanOptions,
+) -> FileScan {
+    let (sender, batches) = mpsc::channel(SCAN_QUEUED_BATCHES);
+    let cache_generation = hash_cache.start_scan();
+    let scan = tokio::task::spawn_blocking(move || {
+        let mut scanner = Scanner::new(&directories, &hash_cache, options);
+        while let Some(batch) = scanner.next_batch() {
+            if sender.blocking_send(batch).is_err() {
+                debug!("The scan of {directories:?} is no longer consumed, stopping it");
+                break;
+            }
+        }
+        Ok(scanner.report)
+    });
+    FileScan {
+        batches,
+        scan,
+        cache_generation,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::path::Path;
+
+    /// Scans the directories at once
+    fn list_directories(
+        directories: Vec<PathBuf>,
+        hash_cache: &HashCache,
+        options: &ScanOptions,
+    ) -> (Vec<FileInfo>, ScanReport) {
+        let mut scanner = Scanner::new(&directories, hash_cache, options.clone());
+        let mut files = Vec::new();
+        while let Some(batch) = scanner.next_batch() {
+            files.extend(batch);
+        }
+        (files, scanner.report)
+    }
 
     #[test]
     fn valid() {
-        let res = list_directories(vec![PathBuf::from("tests/assets/test_folder")]);
-        if let Ok(file_infos) = res {
-            assert!(file_infos.iter().any(|file_info| file_info.pretty_path
-                != PathBuf::from("tests/assets/test_folder/test-file-1")));
-            assert!(file_infos.iter().any(|file_info| file_info.pretty_path
-                != PathBuf::from("tests/assets/test_folder/test-file-10")));
-            assert!(!file_infos
+        let (file_infos, _) = list_directories(
+            vec![PathBuf::from("tests/assets/test_folder")],
+            &HashCache::default(),
+            &ScanOptions::default(),
+        );
+        assert!(file_infos
+            .iter()
+            .any(|file_info| file_info.path != Path::new("tests/assets/test_folder/test-file-1")));
+        assert!(file_infos
+            .iter()
+            .any(|file_info| file_info.path != Path::new("tests/assets/test_folder/test-file-10")));
+        assert!(!file_infos
+            .iter()
+            .any(|file_info| file_info.path == Path::new("file-does-not-exist")));
+    }
+
+    #[test]
+    fn scan_order_is_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b", "a/2", "a/1", "c/d/e", "a/10"] {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        let root = dir.path().canonicalize().unwrap();
+
+        let paths: Vec<PathBuf> = list_directories(
+            vec![dir.path().to_path_buf()],
+            &HashCache::default(),
+            &ScanOptions::default(),
+        )
+        .0
+        .into_iter()
+        .map(|file_info| file_info.path)
+        .collect();
+        assert_eq!(
+            paths,
+            ["a/1", "a/10", "a/2", "b", "c/d/e"]
                 .iter()
-                .any(|file_info| file_info.pretty_path == PathBuf::from("file-does-not-exist")));
+                .map(|name| root.join(name))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_the_scan_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        for index in 0..SCAN_BATCH_SIZE * 2 + 10 {
+            fs::write(dir.path().join(format!("{index:04}")), index.to_string()).unwrap();
         }
+        let hash_cache = Arc::new(HashCache::default());
+        let (listed, _) = list_directories(
+            vec![dir.path().to_path_buf()],
+            &hash_cache,
+            &ScanOptions::default(),
+        );
+
+        let mut scan = stream_directories(
+            vec![dir.path().to_path_buf()],
+            hash_cache.clone(),
+            ScanOptions::default(),
+        );
+        let mut batch_sizes = Vec::new();
+        let mut streamed = Vec::new();
+        while let Some(batch) = scan.next_batch().await {
+            batch_sizes.push(batch.len());
+            streamed.extend(batch);
+        }
+        let report = scan.finish().await.unwrap();
+        assert_eq!(batch_sizes, vec![SCAN_BATCH_SIZE, SCAN_BATCH_SIZE, 10]);
+        // Reading the files may change their access time, only what the scan found is compared
+        let summary = |files: &[FileInfo]| {
+            files
+                .iter()
+                .map(|file| (file.path.clone(), file.hash.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(summary(&streamed), summary(&listed));
+        assert_eq!(report.files_found, listed.len());
+
+        let mut scan = stream_directories(
+            vec![PathBuf::from("file-does-not-exist")],
+            hash_cache,
+            ScanOptions::default(),
+        );
+        assert!(scan.next_batch().await.is_none());
+        assert_eq!(scan.finish().await.unwrap().directories_skipped, 1);
+    }
+
+    fn assert_skipped_root(path: &str, kind: &str) {
+        let (files, report) = list_directories(
+            vec![
+                PathBuf::from(path),
+                PathBuf::from("tests/assets/test_folder"),
+            ],
+            &HashCache::default(),
+            &ScanOptions::default(),
+        );
+        // The other watched directories are still scanned
+        assert!(!files.is_empty());
+        assert_eq!(report.directories_skipped, 1);
+        assert_eq!(report.errors_by_kind.get(kind), Some(&1));
+        assert_eq!(report.errors[0].path, PathBuf::from(path));
     }
 
     #[test]
     fn empty_path() {
-        assert!(matches!(
-            list_directories(vec![PathBuf::from("")]),
-            Err(AgentError::NotADirectory())
-        ));
+        assert_skipped_root("", "NotADirectory");
     }
 
     #[test]
     fn file_does_not_exist() {
-        assert!(matches!(
-            list_directories(vec![PathBuf::from("file-does-not-exist")]),
-            Err(AgentError::NotADirectory())
-        ));
+        assert_skipped_root("file-does-not-exist", "NotADirectory");
     }
 
     #[test]
     fn is_reg_file() {
-        assert!(matches!(
-            list_directories(vec![PathBuf::from("tests/assets/test_folder/test-file-1")]),
-            Err(AgentError::NotADirectory())
-        ));
+        assert_skipped_root("tests/assets/test_folder/test-file-1", "NotADirectory");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_and_special_files() {
+        use crate::file_info::FileKind;
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir/file"), "content").unwrap();
+        fs::hard_link(root.join("dir/file"), root.join("hard_link")).unwrap();
+        symlink(&root, root.join("dir/loop")).unwrap();
+        symlink("dir/file", root.join("link")).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+
+        let scan = |follow_symlinks| {
+            let options = ScanOptions {
+                follow_symlinks,
+                ..Default::default()
+            };
+            let hash_cache = HashCache::default();
+            let (files, _) = list_directories(vec![root.clone()], &hash_cache, &options);
+            // The paths of the file are described from a single read
+            assert_eq!(hash_cache.snapshot().misses, 1);
+            files
+        };
+        let summary = |files: &[FileInfo]| {
+            files
+                .iter()
+                .map(|file| {
+                    (
+                        file.path.strip_prefix(&root).unwrap().to_path_buf(),
+                        file.kind,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let files = scan(false);
+        assert_eq!(
+            summary(&files),
+            vec![
+                (PathBuf::from("dir/file"), FileKind::Regular),
+                (PathBuf::from("dir/loop"), FileKind::Symlink),
+                (PathBuf::from("hard_link"), FileKind::Regular),
+                (PathBuf::from("link"), FileKind::Symlink),
+            ]
+        );
+        assert_eq!(files[1].symlink_target.as_deref(), Some(root.as_path()));
+        assert_eq!(files[0].hash, files[2].hash);
+        assert_eq!(files[3].hash, None);
+
+        // The loop leads back to the scanned directory, it is not scanned again
+        let files = scan(true);
+        assert_eq!(
+            summary(&files),
+            vec![
+                (PathBuf::from("dir/file"), FileKind::Regular),
+                (PathBuf::from("hard_link"), FileKind::Regular),
+                (PathBuf::from("link"), FileKind::Regular),
+            ]
+        );
+        assert!(files.iter().all(|file| file.hash == files[0].hash));
+    }
+
+    #[test]
+    fn ignored_files_are_not_scanned() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for name in [
+            "main.rs",
+            "node_modules/lib.js",
+            "build/out.o",
+            "build/keep.o",
+        ] {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, name).unwrap();
+        }
+        fs::write(root.join(".tidyignore"), "*.o\n!keep.o\n").unwrap();
+
+        let mut config = crate::configuration::Configuration::default().filesystem_interface_config;
+        config.dir = vec![root.clone()];
+        config.ignore_patterns = vec![String::from("node_modules/")];
+        let (files, _) = list_directories(
+            vec![root.clone()],
+            &HashCache::default(),
+            &ScanOptions::new(&config),
+        );
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| file.path.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                root.join(".tidyignore"),
+                root.join("build/keep.o"),
+                root.join("main.rs")
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_directories_are_skipped() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for name in ["a/file", "locked/file", "z/file"] {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, name).unwrap();
+        }
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read_dir(root.join("locked")).is_ok() {
+            // Permissions do not apply to root
+            return;
+        }
+
+        let (files, report) = list_directories(
+            vec![root.clone()],
+            &HashCache::default(),
+            &ScanOptions::default(),
+        );
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| file.path.clone())
+                .collect::<Vec<_>>(),
+            vec![root.join("a/file"), root.join("z/file")]
+        );
+        assert_eq!(
+            (
+                report.files_found,
+                report.directories_scanned,
+                report.directories_skipped
+            ),
+            (2, 3, 1)
+        );
+        assert_eq!(report.errors_by_kind.get("PermissionDenied"), Some(&1));
+        assert_eq!(report.errors[0].path, root.join("locked"));
     }
 }
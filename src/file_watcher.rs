@@ -1,21 +1,96 @@
 // use notify::Watcher;
-use notify_debouncer_full::new_debouncer;
-use std::path::PathBuf;
-use std::sync::mpsc;
+use notify::event::{CreateKind, EventKind, ModifyKind, RemoveKind, RenameMode};
+use notify_debouncer_full::{new_debouncer, DebouncedEvent};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
 use std::time;
-use tokio::sync::mpsc::UnboundedSender;
-use tracing::error;
+use tracing::{debug, error, info};
+
+use crate::event_pipeline::EventSender;
+use crate::ignore_rules::IgnoreRules;
+use crate::stable_writes::StableWrites;
+
+// How often the watcher checks for new directories to watch and for completed writes when no
+// event comes in
+const WATCH_REQUEST_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+type Debouncer = notify_debouncer_full::Debouncer<
+    notify_debouncer_full::notify::INotifyWatcher,
+    notify_debouncer_full::NoCache,
+>;
+
+fn watch_directory(debouncer: &mut Debouncer, directory: &Path) {
+    let clean_directory = match directory.canonicalize() {
+        Ok(clean_directory) => clean_directory,
+        Err(err) => {
+            error!("error with {:?}: {:?}", directory, err);
+            return;
+        }
+    };
+
+    if let Err(err) = debouncer.watch(&clean_directory, notify::RecursiveMode::Recursive) {
+        error!("{:?}: {:?}", clean_directory, err);
+    }
+}
+
+/// Drops the events of ignored paths, after reloading the rules when an ignore file changed.
+/// A rename between an ignored path and a path that is not is seen as a creation or a removal.
+fn apply_ignore_rules(
+    ignore_rules: &IgnoreRules,
+    mut event: DebouncedEvent,
+) -> Option<DebouncedEvent> {
+    for path in &event.paths {
+        if ignore_rules.reload_if_ignore_file(path) {
+            info!("Reloaded the ignore rules of {}", path.display());
+        }
+    }
+    let is_dir = |path: &Path| {
+        path.is_dir()
+            || matches!(
+                event.kind,
+                EventKind::Create(CreateKind::Folder) | EventKind::Remove(RemoveKind::Folder)
+            )
+    };
+    let ignored: Vec<bool> = event
+        .paths
+        .iter()
+        .map(|path| ignore_rules.is_ignored(path, is_dir(path)))
+        .collect();
+
+    match (event.kind, ignored.as_slice()) {
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [true, false]) => {
+            event.paths.remove(0);
+            event.kind = EventKind::Modify(ModifyKind::Name(RenameMode::To));
+            Some(event)
+        }
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [false, true]) => {
+            event.kind = EventKind::Remove(if event.paths[1].is_dir() {
+                RemoveKind::Folder
+            } else {
+                RemoveKind::File
+            });
+            event.paths.truncate(1);
+            Some(event)
+        }
+        (_, [_, ..]) if ignored.iter().all(|ignored| *ignored) => {
+            debug!("Ignoring {:?}", event);
+            None
+        }
+        _ => Some(event),
+    }
+}
 
 pub fn watch_directories(
     directories: Vec<PathBuf>,
-    sender: UnboundedSender<notify_debouncer_full::DebouncedEvent>,
+    sender: EventSender,
+    watch_requests: Receiver<PathBuf>,
+    mut stable_writes: StableWrites,
+    ignore_rules: Arc<IgnoreRules>,
 ) {
     let (tx, rx) = mpsc::channel();
 
-    let mut debouncer: notify_debouncer_full::Debouncer<
-        notify_debouncer_full::notify::INotifyWatcher,
-        notify_debouncer_full::NoCache,
-    > = match new_debouncer(time::Duration::from_secs(2), None, tx) {
+    let mut debouncer: Debouncer = match new_debouncer(time::Duration::from_secs(2), None, tx) {
         Ok(debouncer) => debouncer,
         Err(err) => {
             error!("{:?}", err);
@@ -24,31 +99,45 @@ pub fn watch_directories(
     };
 
     for directory in directories {
-        let clean_directory = match directory.canonicalize() {
-            Ok(clean_directory) => clean_directory,
-            Err(err) => {
-                error!("error with {:?}: {:?}", directory, err);
-                continue;
-            }
-        };
-
-        if let Err(err) = debouncer.watch(&clean_directory, notify::RecursiveMode::Recursive) {
-            error!("{:?}: {:?}", clean_directory, err);
-        }
+        watch_directory(&mut debouncer, &directory);
     }
 
-    for result in rx {
-        match result {
-            Ok(events) => {
-                for event in &events {
-                    sender.send(event.clone()).unwrap();
+    loop {
+        match rx.recv_timeout(WATCH_REQUEST_POLL_INTERVAL) {
+            Ok(Ok(events)) => {
+                let now = time::Instant::now();
+                for event in events {
+                    let event = match apply_ignore_rules(&ignore_rules, event) {
+                        Some(event) => event,
+                        None => continue,
+                    };
+                    for event in stable_writes.filter(event, now) {
+                        if sender.send(event).is_err() {
+                            error!("File event receiver dropped, stopping the file watcher");
+                            return;
+                        }
+                    }
                 }
             }
-            Err(errors) => {
+            Ok(Err(errors)) => {
                 for error in &errors {
                     error!("{error:?}");
                 }
             }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        for event in stable_writes.poll(time::Instant::now()) {
+            if sender.send(event).is_err() {
+                error!("File event receiver dropped, stopping the file watcher");
+                return;
+            }
+        }
+
+        while let Ok(directory) = watch_requests.try_recv() {
+            info!("Starting to watch {}", directory.display());
+            watch_directory(&mut debouncer, &directory);
         }
     }
 }
@@ -0,0 +1,374 @@
+use crate::archive::Listing;
+use crate::configuration::HashAlgorithm;
+use crate::error::{AgentError, FileInfoError};
+use crate::file_info::{self, FileContent};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Identifies a version of a file: the same inode with the same size and modification time is
+/// assumed to have the same content.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+struct CacheKey {
+    device: u64,
+    inode: u64,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl CacheKey {
+    #[cfg(unix)]
+    fn new(md: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        Some(Self {
+            device: md.dev(),
+            inode: md.ino(),
+            size: md.len(),
+            modified: md.modified().ok()?,
+        })
+    }
+
+    // Inode numbers are not exposed by the standard library on other platforms
+    #[cfg(not(unix))]
+    fn new(_md: &Metadata) -> Option<Self> {
+        None
+    }
+}
+
+struct CacheEntry {
+    content: FileContent,
+    // Generation of the last lookup, 0 for the entries loaded and not looked up yet
+    used: u64,
+    // Entries of an archive, only kept in memory
+    listing: Option<Listing>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    hashes: HashMap<CacheKey, CacheEntry>,
+    // Incremented at the start of every full scan
+    generation: u64,
+    dirty: bool,
+}
+
+impl CacheEntries {
+    fn insert(&mut self, key: CacheKey, content: FileContent) {
+        let used = self.generation;
+        self.hashes.insert(
+            key,
+            CacheEntry {
+                content,
+                used,
+                listing: None,
+            },
+        );
+        self.dirty = true;
+    }
+}
+
+// Bumped when FileContent changes, so that entries missing the new data are computed again
+const CACHE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct StoredCache {
+    #[serde(default)]
+    version: u32,
+    algorithm: HashAlgorithm,
+    entries: Vec<(CacheKey, FileContent)>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HashCacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// Persistent cache of the file hashes and types, so that unchanged files are not read again
+/// when the agent restarts. Shared between the scanner and the event handlers.
+#[derive(Default)]
+pub struct HashCache {
+    path: PathBuf,
+    algorithm: HashAlgorithm,
+    entries: Mutex<CacheEntries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl HashCache {
+    /// Loads the cache, it starts empty when the file is missing, unreadable, was computed with
+    /// another algorithm or by an older version of the agent.
+    pub fn load(path: &Path, algorithm: HashAlgorithm) -> Self {
+        let hashes = match fs::read(path) {
+            Ok(content) => match serde_json::from_slice::<StoredCache>(&content) {
+                Ok(stored) if stored.version == CACHE_VERSION && stored.algorithm == algorithm => {
+                    stored
+                        .entries
+                        .into_iter()
+                        .map(|(key, content)| {
+                            let entry = CacheEntry {
+                                content,
+                                used: 0,
+                                listing: None,
+                            };
+                            (key, entry)
+                        })
+                        .collect()
+                }
+                Ok(_) => HashMap::new(),
+                Err(err) => {
+                    warn!(
+                        "Discarding the unreadable hash cache {}: {err}",
+                        path.display()
+                    );
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path: path.to_path_buf(),
+            algorithm,
+            entries: Mutex::new(CacheEntries {
+                hashes,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the hash and type of the file described by `md`, reading the file only when it
+    /// changed since it was last read.
+    pub fn file_content(&self, path: &Path, md: &Metadata) -> Result<FileContent, FileInfoError> {
+        let key = match CacheKey::new(md) {
+            Some(key) => key,
+            None => return file_info::read_file_content(path, self.algorithm),
+        };
+
+        let cached = {
+            let mut entries = self.entries.lock().unwrap();
+            let generation = entries.generation;
+            entries.hashes.get_mut(&key).map(|entry| {
+                entry.used = generation;
+                entry.content.clone()
+            })
+        };
+        if let Some(content) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(content);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let content = file_info::read_file_content(path, self.algorithm)?;
+        self.entries.lock().unwrap().insert(key, content.clone());
+        Ok(content)
+    }
+
+    /// Reads the file whatever the cache holds and replaces its entry, for when the cached hash
+    /// is suspected to be wrong.
+    pub fn refresh(&self, path: &Path, md: &Metadata) -> Result<FileContent, FileInfoError> {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let content = file_info::read_file_content(path, self.algorithm)?;
+        if let Some(key) = CacheKey::new(md) {
+            self.entries.lock().unwrap().insert(key, content.clone());
+        }
+        Ok(content)
+    }
+
+    /// Entries of the archive described by `md` when it was listed since it last changed.
+    pub fn archive_listing(&self, md: &Metadata) -> Option<Listing> {
+        let key = CacheKey::new(md)?;
+        let entries = self.entries.lock().unwrap();
+        entries.hashes.get(&key)?.listing.clone()
+    }
+
+    /// Keeps the entries of the archive described by `md` with its hash, until it changes.
+    pub fn set_archive_listing(&self, md: &Metadata, listing: Listing) {
+        let key = match CacheKey::new(md) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(entry) = self.entries.lock().unwrap().hashes.get_mut(&key) {
+            entry.listing = Some(listing);
+        }
+    }
+
+    /// Starts a new generation, returned to `prune` once the scan looked up every file.
+    pub fn start_scan(&self) -> u64 {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.generation
+    }
+
+    /// Drops the entries that were not used since `generation` started. Called after a full
+    /// scan, when every file that still exists has been looked up.
+    pub fn prune(&self, generation: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.hashes.len();
+        entries.hashes.retain(|_, entry| entry.used >= generation);
+        if entries.hashes.len() != count {
+            entries.dirty = true;
+        }
+    }
+
+    /// Writes the cache to disk if it changed since the last save.
+    pub fn save(&self) -> Result<(), AgentError> {
+        let stored = {
+            let mut entries = self.entries.lock().unwrap();
+            if !entries.dirty {
+                return Ok(());
+            }
+            entries.dirty = false;
+            StoredCache {
+                version: CACHE_VERSION,
+                algorithm: self.algorithm,
+                entries: entries
+                    .hashes
+                    .iter()
+                    .map(|(key, entry)| (*key, entry.content.clone()))
+                    .collect(),
+            }
+        };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(
+            &temporary_path,
+            serde_json::to_vec(&stored).map_err(std::io::Error::from)?,
+        )?;
+        fs::rename(&temporary_path, &self.path)?;
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> HashCacheMetrics {
+        HashCacheMetrics {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().hashes.len(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_hashes_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("hash_cache.json");
+        let file = dir.path().join("file");
+        let other = dir.path().join("other");
+        fs::write(&file, "content").unwrap();
+        fs::write(&other, "other").unwrap();
+
+        let cache = HashCache::load(&cache_path, HashAlgorithm::Xxh3_64);
+        let hash = cache
+            .file_content(&file, &fs::metadata(&file).unwrap())
+            .unwrap()
+            .hash;
+        cache
+            .file_content(&other, &fs::metadata(&other).unwrap())
+            .unwrap();
+        cache.save().unwrap();
+        assert_eq!(cache.snapshot().misses, 2);
+
+        fs::remove_file(&other).unwrap();
+        let cache = HashCache::load(&cache_path, HashAlgorithm::Xxh3_64);
+        let generation = cache.start_scan();
+        assert_eq!(
+            cache
+                .file_content(&file, &fs::metadata(&file).unwrap())
+                .unwrap()
+                .hash,
+            hash
+        );
+        cache.prune(generation);
+        let metrics = cache.snapshot();
+        assert_eq!((metrics.hits, metrics.misses, metrics.entries), (1, 0, 1));
+
+        // Another algorithm cannot reuse the stored hashes
+        let cache = HashCache::load(&cache_path, HashAlgorithm::Sha256);
+        assert_eq!(cache.snapshot().entries, 0);
+    }
+
+    #[test]
+    fn prunes_the_entries_missed_by_the_last_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "content").unwrap();
+
+        let cache = HashCache::load(&dir.path().join("hash_cache.json"), HashAlgorithm::Xxh3_64);
+        let generation = cache.start_scan();
+        cache
+            .file_content(&file, &fs::metadata(&file).unwrap())
+            .unwrap();
+        fs::write(&file, "changed content").unwrap();
+        cache
+            .file_content(&file, &fs::metadata(&file).unwrap())
+            .unwrap();
+        cache.prune(generation);
+        assert_eq!(cache.snapshot().entries, 2);
+
+        // The next scan only looks up the current version of the file
+        let generation = cache.start_scan();
+        cache
+            .file_content(&file, &fs::metadata(&file).unwrap())
+            .unwrap();
+        cache.prune(generation);
+        assert_eq!(cache.snapshot().entries, 1);
+    }
+
+    #[test]
+    fn changed_file_is_hashed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "content").unwrap();
+
+        let cache = HashCache::load(&dir.path().join("hash_cache.json"), HashAlgorithm::Xxh3_64);
+        let before = cache
+            .file_content(&file, &fs::metadata(&file).unwrap())
+            .unwrap()
+            .hash;
+        fs::write(&file, "changed content").unwrap();
+        let after = cache
+            .file_content(&file, &fs::metadata(&file).unwrap())
+            .unwrap()
+            .hash;
+
+        assert_ne!(before, after);
+        assert_eq!(cache.snapshot().misses, 2);
+    }
+
+    #[test]
+    fn refresh_replaces_the_cached_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "content").unwrap();
+        let md = fs::metadata(&file).unwrap();
+
+        let cache = HashCache::load(&dir.path().join("hash_cache.json"), HashAlgorithm::Xxh3_64);
+        let hash = cache.file_content(&file, &md).unwrap().hash;
+        // Corrupt the entry, as a bad disk or an edit keeping the size and time would
+        let key = CacheKey::new(&md).unwrap();
+        let mut entries = cache.entries.lock().unwrap();
+        entries.hashes.get_mut(&key).unwrap().content.hash = String::from("stale");
+        drop(entries);
+
+        assert_eq!(cache.refresh(&file, &md).unwrap().hash, hash);
+        assert_eq!(cache.file_content(&file, &md).unwrap().hash, hash);
+        assert_eq!(cache.snapshot().misses, 2);
+    }
+}
@@ -0,0 +1,541 @@
+use crate::error::AgentError;
+use crate::http::grpc::tidybee_events::{FileEventRequest, FolderEventRequest};
+use prost::Message;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const FILE_EVENT_TAG: u8 = 1;
+const FOLDER_EVENT_TAG: u8 = 2;
+// tag (1 byte) + payload length (4 bytes, little endian)
+const RECORD_HEADER_LEN: usize = 5;
+
+// Nearly every queued event is a file event, boxing them would only add an allocation
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueuedEvent {
+    File(FileEventRequest),
+    Folder(FolderEventRequest),
+}
+
+impl QueuedEvent {
+    #[inline]
+    pub fn sequence(&self) -> u64 {
+        match self {
+            QueuedEvent::File(event) => event.sequence,
+            QueuedEvent::Folder(event) => event.sequence,
+        }
+    }
+
+    fn set_sequence(&mut self, sequence: u64) {
+        match self {
+            QueuedEvent::File(event) => event.sequence = sequence,
+            QueuedEvent::Folder(event) => event.sequence = sequence,
+        }
+    }
+
+    fn encode_record(&self) -> Vec<u8> {
+        let (tag, payload) = match self {
+            QueuedEvent::File(event) => (FILE_EVENT_TAG, event.encode_to_vec()),
+            QueuedEvent::Folder(event) => (FOLDER_EVENT_TAG, event.encode_to_vec()),
+        };
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.push(tag);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    fn decode_record(tag: u8, payload: &[u8]) -> Option<Self> {
+        match tag {
+            FILE_EVENT_TAG => FileEventRequest::decode(payload)
+                .ok()
+                .map(QueuedEvent::File),
+            FOLDER_EVENT_TAG => FolderEventRequest::decode(payload)
+                .ok()
+                .map(QueuedEvent::Folder),
+            _ => None,
+        }
+    }
+}
+
+/// Append-only write-ahead log of the events that still have to be delivered to the Hub.
+///
+/// Every event is written (and synced) to disk before being sent, so that events survive both
+/// network failures and agent restarts. The sequence acknowledged by the Hub is saved next to the
+/// log, and the acknowledged records are only removed from the log once they add up to
+/// `COMPACTION_THRESHOLD` bytes or the whole log was acknowledged.
+///
+/// Only the oldest `RESIDENT_EVENTS` events are kept in memory, the next ones are read back from
+/// the log as the older ones are acknowledged.
+///
+/// The queue also numbers the events: each one gets the next value of a per-agent sequence,
+/// which is persisted next to the log whenever the log is emptied.
+pub struct EventQueue {
+    path: PathBuf,
+    file: File,
+    log_len: u64,
+    // Oldest events not acknowledged yet
+    resident: VecDeque<QueuedEvent>,
+    // Events following the resident ones, only in the log from `unloaded_offset` onwards
+    unloaded: usize,
+    unloaded_offset: u64,
+    // Length of the acknowledged records at the start of the log
+    acknowledged_len: u64,
+    next_sequence: u64,
+    resident_limit: usize,
+    compaction_threshold: u64,
+}
+
+const RESIDENT_EVENTS: usize = 10_000;
+const COMPACTION_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+impl EventQueue {
+    pub fn open(path: &Path) -> Result<Self, AgentError> {
+        Self::open_with(path, RESIDENT_EVENTS, COMPACTION_THRESHOLD)
+    }
+
+    fn open_with(
+        path: &Path,
+        resident_limit: usize,
+        compaction_threshold: u64,
+    ) -> Result<Self, AgentError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut queue = Self {
+            path: path.to_path_buf(),
+            file,
+            log_len: 0,
+            resident: VecDeque::new(),
+            unloaded: 0,
+            unloaded_offset: 0,
+            acknowledged_len: 0,
+            next_sequence: Self::read_number(&Self::sequence_path(path)).unwrap_or(1),
+            resident_limit,
+            compaction_threshold,
+        };
+        let acknowledged = Self::read_number(&Self::acknowledged_path(path)).unwrap_or(0);
+        let mut reader = BufReader::new(File::open(path)?);
+        while let Some((event, len)) = read_record(&mut reader)? {
+            queue.next_sequence = queue.next_sequence.max(event.sequence() + 1);
+            if event.sequence() <= acknowledged {
+                queue.acknowledged_len += len;
+            } else if queue.unloaded == 0 && queue.resident.len() < resident_limit {
+                queue.resident.push_back(event);
+            } else {
+                if queue.unloaded == 0 {
+                    queue.unloaded_offset = queue.log_len;
+                }
+                queue.unloaded += 1;
+            }
+            queue.log_len += len;
+        }
+
+        let file_len = queue.file.metadata()?.len();
+        if queue.log_len < file_len {
+            warn!(
+                "Discarding {} trailing bytes of corrupted data in the event queue {}",
+                file_len - queue.log_len,
+                path.display()
+            );
+            queue.file.set_len(queue.log_len)?;
+            queue.file.sync_data()?;
+        }
+        if queue.is_empty() {
+            queue.clear_log()?;
+        } else {
+            info!(
+                "Replaying {} undelivered events from {}",
+                queue.len(),
+                path.display()
+            );
+        }
+        Ok(queue)
+    }
+
+    #[inline]
+    fn sequence_path(path: &Path) -> PathBuf {
+        path.with_extension("seq")
+    }
+
+    #[inline]
+    fn acknowledged_path(path: &Path) -> PathBuf {
+        path.with_extension("ack")
+    }
+
+    fn read_number(path: &Path) -> Option<u64> {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| content.trim().parse().ok())
+    }
+
+    fn write_number(path: &Path, number: u64) -> Result<(), AgentError> {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(number.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn push(&mut self, event: QueuedEvent) -> Result<(), AgentError> {
+        self.extend([event])
+    }
+
+    /// Appends several events at once, syncing the log a single time.
+    pub fn extend<I: IntoIterator<Item = QueuedEvent>>(
+        &mut self,
+        events: I,
+    ) -> Result<(), AgentError> {
+        let mut records = Vec::new();
+        // Events with the offset of their record in the log
+        let mut numbered = Vec::new();
+        let mut next_sequence = self.next_sequence;
+        for mut event in events {
+            event.set_sequence(next_sequence);
+            next_sequence += 1;
+            let offset = self.log_len + records.len() as u64;
+            records.extend_from_slice(&event.encode_record());
+            numbered.push((event, offset));
+        }
+        self.file.write_all(&records)?;
+        self.file.sync_data()?;
+
+        for (event, offset) in numbered {
+            if self.unloaded == 0 && self.resident.len() < self.resident_limit {
+                self.resident.push_back(event);
+            } else {
+                if self.unloaded == 0 {
+                    self.unloaded_offset = offset;
+                }
+                self.unloaded += 1;
+            }
+        }
+        self.log_len += records.len() as u64;
+        self.next_sequence = next_sequence;
+        Ok(())
+    }
+
+    /// Returns the event at `index` in the queue, reading it back from the log if needed.
+    pub fn get(&mut self, index: usize) -> Result<Option<&QueuedEvent>, AgentError> {
+        if index >= self.resident.len() && self.unloaded > 0 {
+            self.load(index + 1)?;
+        }
+        Ok(self.resident.get(index))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.resident.len() + self.unloaded
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every pending event up to the `sequence` acknowledged by the Hub.
+    pub fn acknowledge_up_to(&mut self, sequence: u64) -> Result<(), AgentError> {
+        let count = self
+            .resident
+            .iter()
+            .take_while(|event| event.sequence() <= sequence)
+            .count();
+        self.acknowledge(count)
+    }
+
+    /// Removes the `count` oldest events from the queue once the Hub acknowledged them. Only the
+    /// events read with `get` can be acknowledged.
+    pub fn acknowledge(&mut self, count: usize) -> Result<(), AgentError> {
+        let count = count.min(self.resident.len());
+        if count == 0 {
+            return Ok(());
+        }
+        let mut last_sequence = 0;
+        for event in self.resident.drain(..count) {
+            self.acknowledged_len += record_len(&event);
+            last_sequence = event.sequence();
+        }
+
+        if self.is_empty() {
+            return self.clear_log();
+        }
+        Self::write_number(&Self::acknowledged_path(&self.path), last_sequence)?;
+        if self.acknowledged_len >= self.compaction_threshold {
+            self.compact()?;
+        }
+        if self.resident.is_empty() {
+            self.load(self.resident_limit)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next unloaded events from the log, so that at least `count` events are resident
+    /// when there are enough of them.
+    fn load(&mut self, count: usize) -> Result<(), AgentError> {
+        let target = count.max(self.resident_limit).min(self.len());
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(self.unloaded_offset))?;
+        while self.resident.len() < target {
+            let (event, len) = match read_record(&mut reader)? {
+                Some(record) => record,
+                None => {
+                    return Err(AgentError::Io(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("the event queue {} is truncated", self.path.display()),
+                    )))
+                }
+            };
+            self.resident.push_back(event);
+            self.unloaded -= 1;
+            self.unloaded_offset += len;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log without its acknowledged records in a temporary file and atomically swaps
+    /// it in, so that a crash in the middle of the compaction never loses events.
+    fn compact(&mut self) -> Result<(), AgentError> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        let mut log = File::open(&self.path)?;
+        log.seek(SeekFrom::Start(self.acknowledged_len))?;
+        std::io::copy(&mut log, &mut tmp_file)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+
+        self.log_len -= self.acknowledged_len;
+        self.unloaded_offset = self.unloaded_offset.saturating_sub(self.acknowledged_len);
+        self.acknowledged_len = 0;
+        Ok(())
+    }
+
+    /// Empties the log once every event was acknowledged.
+    fn clear_log(&mut self) -> Result<(), AgentError> {
+        if self.log_len == 0 {
+            return Ok(());
+        }
+        // The log no longer holds the latest sequence number, save it before emptying it
+        Self::write_number(&Self::sequence_path(&self.path), self.next_sequence)?;
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.log_len = 0;
+        self.acknowledged_len = 0;
+        self.unloaded_offset = 0;
+        Ok(())
+    }
+}
+
+#[inline]
+fn record_len(event: &QueuedEvent) -> u64 {
+    let payload_len = match event {
+        QueuedEvent::File(event) => event.encoded_len(),
+        QueuedEvent::Folder(event) => event.encoded_len(),
+    };
+    (RECORD_HEADER_LEN + payload_len) as u64
+}
+
+/// Reads the next record of the log and its length, None at the end of the log or when the
+/// record was only partially written.
+fn read_record(reader: &mut impl Read) -> std::io::Result<Option<(QueuedEvent, u64)>> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut len_bytes = [0; 4];
+    len_bytes.copy_from_slice(&header[1..]);
+    let len = u32::from_le_bytes(len_bytes) as u64;
+    // The length is not trusted to allocate the payload, the record may be corrupted
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Ok(None);
+    }
+    Ok(QueuedEvent::decode_record(header[0], &payload)
+        .map(|event| (event, RECORD_HEADER_LEN as u64 + len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::grpc::tidybee_events::FileEventType;
+
+    fn file_event(path: &str) -> QueuedEvent {
+        QueuedEvent::File(FileEventRequest {
+            event_type: FileEventType::Created as i32,
+            pretty_path: path.to_owned(),
+            path: vec![path.to_owned()],
+            ..Default::default()
+        })
+    }
+
+    fn folder_event(path: &str) -> QueuedEvent {
+        QueuedEvent::Folder(FolderEventRequest {
+            event_type: FileEventType::Deleted as i32,
+            old_path: path.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    fn numbered(mut event: QueuedEvent, sequence: u64) -> QueuedEvent {
+        event.set_sequence(sequence);
+        event
+    }
+
+    fn events(queue: &mut EventQueue) -> Vec<QueuedEvent> {
+        (0..queue.len())
+            .map(|index| queue.get(index).unwrap().unwrap().clone())
+            .collect()
+    }
+
+    #[test]
+    fn replays_unacknowledged_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbound_events.wal");
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        queue.push(file_event("/a")).unwrap();
+        queue.push(folder_event("/b")).unwrap();
+        queue.push(file_event("/c")).unwrap();
+        queue.acknowledge(1).unwrap();
+        drop(queue);
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        assert_eq!(
+            events(&mut queue),
+            vec![
+                numbered(folder_event("/b"), 2),
+                numbered(file_event("/c"), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn truncates_when_everything_is_acknowledged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbound_events.wal");
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        queue.push(file_event("/a")).unwrap();
+        queue.push(file_event("/b")).unwrap();
+        queue.acknowledge(2).unwrap();
+
+        assert!(queue.is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn numbers_events_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbound_events.wal");
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        queue.push(file_event("/a")).unwrap();
+        queue
+            .extend(vec![folder_event("/b"), file_event("/c")])
+            .unwrap();
+        assert_eq!(
+            events(&mut queue)
+                .iter()
+                .map(QueuedEvent::sequence)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        queue.acknowledge_up_to(2).unwrap();
+        assert_eq!(queue.len(), 1);
+        drop(queue);
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        queue.acknowledge_up_to(3).unwrap();
+        drop(queue);
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        queue.push(file_event("/d")).unwrap();
+        assert_eq!(events(&mut queue)[0].sequence(), 4);
+    }
+
+    #[test]
+    fn discards_partially_written_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbound_events.wal");
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        queue.push(file_event("/a")).unwrap();
+        drop(queue);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[FILE_EVENT_TAG, 42, 0]).unwrap();
+        drop(file);
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        assert_eq!(queue.len(), 1);
+        queue.push(file_event("/b")).unwrap();
+        drop(queue);
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        assert_eq!(
+            events(&mut queue),
+            vec![numbered(file_event("/a"), 1), numbered(file_event("/b"), 2)]
+        );
+    }
+
+    #[test]
+    fn keeps_acknowledged_records_until_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbound_events.wal");
+        let record_len = numbered(file_event("/a"), 1).encode_record().len() as u64;
+
+        let mut queue = EventQueue::open_with(&path, RESIDENT_EVENTS, 2 * record_len).unwrap();
+        queue
+            .extend(vec![file_event("/a"), file_event("/a"), file_event("/a")])
+            .unwrap();
+        queue.acknowledge(1).unwrap();
+        // Only the acknowledged sequence was written
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3 * record_len);
+        queue.acknowledge(1).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), record_len);
+        drop(queue);
+
+        let mut queue = EventQueue::open(&path).unwrap();
+        assert_eq!(events(&mut queue), vec![numbered(file_event("/a"), 3)]);
+    }
+
+    #[test]
+    fn reads_the_backlog_back_from_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbound_events.wal");
+        let paths = ["/a", "/b", "/c", "/d", "/e"];
+
+        let mut queue = EventQueue::open_with(&path, 2, COMPACTION_THRESHOLD).unwrap();
+        queue.push(file_event(paths[0])).unwrap();
+        queue
+            .extend(paths[1..].iter().map(|path| file_event(path)))
+            .unwrap();
+        assert_eq!((queue.resident.len(), queue.len()), (2, 5));
+        queue.acknowledge(2).unwrap();
+        assert_eq!(queue.resident.len(), 2);
+        assert_eq!(queue.get(2).unwrap(), Some(&numbered(file_event("/e"), 5)));
+        drop(queue);
+
+        let mut queue = EventQueue::open_with(&path, 2, COMPACTION_THRESHOLD).unwrap();
+        assert_eq!(
+            events(&mut queue),
+            vec![
+                numbered(file_event("/c"), 3),
+                numbered(file_event("/d"), 4),
+                numbered(file_event("/e"), 5)
+            ]
+        );
+    }
+}
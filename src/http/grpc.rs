@@ -1,31 +1,67 @@
 use self::tidybee_events::{FileEventRequest, FileEventType};
 use crate::{
-    configuration::GrpcServerConfig,
+    archive,
+    configuration::{GrpcCompression, GrpcServerConfig},
+    content_type::FileCategory,
+    duplicates::DuplicateIndex,
     error::GrpcClientError,
-    file_info::{self, FileInfo},
-    file_lister,
+    event_pipeline::EventReceiver,
+    file_info::{self, FileInfo, FileKind},
+    file_lister::{self, FileScan, ScanOptions},
+    hash_cache::HashCache,
+    http::event_queue::{EventQueue, QueuedEvent},
+    http::tls,
+    inventory::{FileChange, Inventory, Reconciliation},
+    media_metadata::MediaMetadata,
+    scan_report::{ScanReport, SkippedPaths},
+    watched_root::WatchedRoots,
 };
 
 use anyhow::{bail, ensure, Error, Result};
+use gethostname::gethostname;
 use notify::event::ModifyKind;
-use notify_debouncer_full::DebouncedEvent;
-use std::{str::FromStr, vec};
-use tidybee_events::{tidy_bee_events_client::TidyBeeEventsClient, FolderEventRequest};
-use tokio::sync::mpsc::UnboundedReceiver;
+use rand::Rng;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+    vec,
+};
+use tidybee_events::{
+    file_event_request::Media as ProtoMedia, hub_command::Command,
+    tidy_bee_events_client::TidyBeeEventsClient, AgentData, AudioVideoMetadata as ProtoAudioVideo,
+    CommandResult, FileCategory as ProtoFileCategory, FileInfoEventResponse,
+    FileKind as ProtoFileKind, FolderEventRequest, HubCommand, ImageMetadata as ProtoImageMetadata,
+    PosixMetadata as ProtoPosixMetadata, ScanError as ProtoScanError, ScanReportRequest,
+    Status as EventStatus, WatchedRoot as ProtoWatchedRoot,
+};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{
+    codec::CompressionEncoding,
     metadata::MetadataValue,
     service::Interceptor,
     transport::{Channel, Endpoint},
-    Request, Status,
+    Code, Request, Response, Status,
 };
 use tracing::{debug, info, warn};
 
+#[allow(dead_code)]
 pub mod tidybee_events {
     tonic::include_proto!("tidybee_events");
 }
 
 // region: --- Interceptors
 
+#[derive(Clone)]
 pub struct AuthInterceptor {
     agent_uuid: String,
 }
@@ -52,6 +88,320 @@ impl Interceptor for AuthInterceptor {
 
 // endregion: --- Interceptors
 
+// region: --- Event builders
+
+fn proto_category(category: FileCategory) -> ProtoFileCategory {
+    match category {
+        FileCategory::Other => ProtoFileCategory::Other,
+        FileCategory::Document => ProtoFileCategory::Document,
+        FileCategory::Image => ProtoFileCategory::Image,
+        FileCategory::Video => ProtoFileCategory::Video,
+        FileCategory::Audio => ProtoFileCategory::Audio,
+        FileCategory::Archive => ProtoFileCategory::Archive,
+        FileCategory::Code => ProtoFileCategory::Code,
+        FileCategory::Executable => ProtoFileCategory::Executable,
+        FileCategory::Font => ProtoFileCategory::Font,
+    }
+}
+
+fn proto_scan_report(report: &ScanReport) -> ScanReportRequest {
+    ScanReportRequest {
+        directories: report
+            .directories
+            .iter()
+            .map(|directory| directory.display().to_string())
+            .collect(),
+        started_at: Some(report.started_at.into()),
+        duration_ms: report.duration_ms,
+        files_found: report.files_found as u64,
+        files_skipped: report.files_skipped as u64,
+        directories_scanned: report.directories_scanned as u64,
+        directories_skipped: report.directories_skipped as u64,
+        errors_by_kind: report
+            .errors_by_kind
+            .iter()
+            .map(|(kind, count)| (kind.clone(), *count as u64))
+            .collect(),
+        errors: report
+            .errors
+            .iter()
+            .map(|error| ProtoScanError {
+                path: error.path.display().to_string(),
+                kind: error.kind.clone(),
+                message: error.message.clone(),
+            })
+            .collect(),
+    }
+}
+
+fn proto_kind(kind: FileKind) -> ProtoFileKind {
+    match kind {
+        FileKind::Regular => ProtoFileKind::Regular,
+        FileKind::Symlink => ProtoFileKind::Symlink,
+    }
+}
+
+fn proto_media(media: MediaMetadata) -> ProtoMedia {
+    match media {
+        MediaMetadata::Image(image) => ProtoMedia::Image(ProtoImageMetadata {
+            captured_at: image.captured_at,
+            camera_make: image.camera_make,
+            camera_model: image.camera_model,
+            has_gps: image.has_gps,
+            orientation: image.orientation,
+            width: image.width,
+            height: image.height,
+        }),
+        MediaMetadata::AudioVideo(audio_video) => ProtoMedia::AudioVideo(ProtoAudioVideo {
+            duration_ms: audio_video.duration_ms,
+            codec: audio_video.codec,
+            title: audio_video.title,
+            artist: audio_video.artist,
+            album: audio_video.album,
+        }),
+    }
+}
+
+fn file_info_event(
+    event_type: FileEventType,
+    info: FileInfo,
+    roots: &WatchedRoots,
+) -> FileEventRequest {
+    let located = roots.locate(&info.path);
+    let mut pretty_path = located.pretty_path.to_path_buf();
+    let mut path = vec![info.path.display().to_string()];
+    if let Some(inner_path) = &info.inner_path {
+        pretty_path.push(inner_path);
+        path.push(inner_path.display().to_string());
+    }
+    FileEventRequest {
+        event_type: event_type as i32,
+        pretty_path: pretty_path.display().to_string(),
+        root_id: located.root_id.map(String::from),
+        path,
+        size: Some(info.size),
+        hash_algorithm: info
+            .hash
+            .as_ref()
+            .map(|_| String::from(info.hash_algorithm.name())),
+        hash: info.hash,
+        mime_type: info.mime_type,
+        category: proto_category(info.category) as i32,
+        last_accessed: Some(info.last_accessed.into()),
+        last_modified: Some(info.last_modified.into()),
+        created: info.created.map(Into::into),
+        posix: info.posix.map(|posix| ProtoPosixMetadata {
+            uid: posix.uid,
+            gid: posix.gid,
+            user: posix.user,
+            group: posix.group,
+            mode: posix.mode,
+            inode: posix.inode,
+            device: posix.device,
+            hard_links: posix.hard_links,
+        }),
+        media: info.media.map(proto_media),
+        kind: proto_kind(info.kind) as i32,
+        symlink_target: info
+            .symlink_target
+            .map(|target| target.display().to_string()),
+        // Assigned by the event queue
+        sequence: 0,
+    }
+}
+
+fn deleted_event(path: &Path, inner_path: Option<&Path>, roots: &WatchedRoots) -> FileEventRequest {
+    let located = roots.locate(path);
+    let mut pretty_path = located.pretty_path.to_path_buf();
+    let mut paths = vec![path.display().to_string()];
+    if let Some(inner_path) = inner_path {
+        pretty_path.push(inner_path);
+        paths.push(inner_path.display().to_string());
+    }
+    FileEventRequest {
+        event_type: FileEventType::Deleted as i32,
+        pretty_path: pretty_path.display().to_string(),
+        root_id: located.root_id.map(String::from),
+        path: paths,
+        size: None,
+        hash: None,
+        hash_algorithm: None,
+        mime_type: None,
+        category: ProtoFileCategory::Other as i32,
+        last_accessed: None,
+        last_modified: None,
+        created: None,
+        posix: None,
+        media: None,
+        kind: ProtoFileKind::Regular as i32,
+        symlink_target: None,
+        sequence: 0,
+    }
+}
+
+fn folder_event(
+    event_type: FileEventType,
+    old_path: &Path,
+    new_path: Option<&Path>,
+    roots: &WatchedRoots,
+) -> FolderEventRequest {
+    let located = roots.locate(old_path);
+    FolderEventRequest {
+        event_type: event_type as i32,
+        old_path: old_path.display().to_string(),
+        new_path: new_path.map(|path| path.display().to_string()),
+        sequence: 0,
+        root_id: located.root_id.map(String::from),
+        old_pretty_path: located.pretty_path.display().to_string(),
+        new_pretty_path: new_path.map(|path| roots.locate(path).pretty_path.display().to_string()),
+    }
+}
+
+// endregion: --- Event builders
+
+// region: --- Connection state
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Disconnected,
+    Connected,
+    Reconnecting,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct GrpcStatus {
+    pub state: ConnectionState,
+    pub reconnect_attempts: u32,
+    pub last_acknowledged_sequence: u64,
+}
+
+impl Default for GrpcStatus {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::Disconnected,
+            reconnect_attempts: 0,
+            last_acknowledged_sequence: 0,
+        }
+    }
+}
+
+/// Exponential backoff with "equal jitter": the delay is picked randomly in the upper half of
+/// the exponential window, so that agents disconnected together do not reconnect together.
+struct Backoff {
+    base_delay: Duration,
+    max_delay: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    fn new(base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            base_delay,
+            max_delay: max_delay.max(base_delay),
+            attempt: 0,
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let window = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max_delay);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = window / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+fn is_transport_failure(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::Unknown | Code::Cancelled | Code::DeadlineExceeded
+    )
+}
+
+// endregion: --- Connection state
+
+// region: --- Command channel
+
+const COMMAND_CHANNEL_CAPACITY: usize = 16;
+
+struct CommandChannel {
+    commands: mpsc::Receiver<HubCommand>,
+    results: mpsc::Sender<CommandResult>,
+}
+
+async fn next_command(channel: &mut Option<CommandChannel>) -> Option<HubCommand> {
+    match channel {
+        Some(channel) => channel.commands.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// endregion: --- Command channel
+
+// region: --- Event streams
+
+enum StreamSender {
+    File(mpsc::Sender<FileEventRequest>),
+    Folder(mpsc::Sender<FolderEventRequest>),
+}
+
+/// A client-streaming call kept open while events keep flowing, so that a burst of events
+/// travels over a single HTTP/2 stream instead of one RPC per event.
+struct EventStream {
+    sender: StreamSender,
+    response: JoinHandle<Result<FileInfoEventResponse, Status>>,
+    sent: usize,
+    last_sent: Instant,
+}
+
+impl EventStream {
+    #[inline]
+    fn accepts(&self, event: &QueuedEvent) -> bool {
+        matches!(
+            (&self.sender, event),
+            (StreamSender::File(_), QueuedEvent::File(_))
+                | (StreamSender::Folder(_), QueuedEvent::Folder(_))
+        )
+    }
+
+    async fn send(&mut self, event: QueuedEvent) -> Result<(), GrpcClientError> {
+        let result = match (&self.sender, event) {
+            (StreamSender::File(sender), QueuedEvent::File(event)) => {
+                sender.send(event).await.map_err(|_| ())
+            }
+            (StreamSender::Folder(sender), QueuedEvent::Folder(event)) => {
+                sender.send(event).await.map_err(|_| ())
+            }
+            _ => Err(()),
+        };
+        if result.is_err() {
+            return Err(GrpcClientError::EventSendError());
+        }
+        self.sent += 1;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<FileInfoEventResponse, Status> {
+        // Dropping the sender ends the request stream, the Hub then sends its response
+        drop(self.sender);
+        match self.response.await {
+            Ok(response) => response,
+            Err(e) => Err(Status::internal(format!("Event stream task failed: {e}"))),
+        }
+    }
+}
+
+// endregion: --- Event streams
+
 pub struct GrpcClient {
     pub client: Option<
         TidyBeeEventsClient<
@@ -60,18 +410,80 @@ pub struct GrpcClient {
     >,
     agent_uuid: Option<String>,
     endpoint: Endpoint,
+    queue: EventQueue,
+    inventory: Inventory,
+    stream: Option<EventStream>,
+    in_flight: usize,
+    batch_size: usize,
+    flush_interval: Duration,
+    save_interval: Duration,
+    backoff: Backoff,
+    next_reconnect: Instant,
+    status: Arc<Mutex<GrpcStatus>>,
+    command_channel: Option<CommandChannel>,
+    roots: WatchedRoots,
+    watch_requests: Option<std::sync::mpsc::Sender<PathBuf>>,
+    // Directories under which the Hub may ask for a new directory to be watched
+    allowed_watch_roots: WatchedRoots,
+    compression: Option<CompressionEncoding>,
+    hash_cache: Arc<HashCache>,
+    scan_options: ScanOptions,
+    scan_report: Arc<Mutex<Option<ScanReport>>>,
+    send_scan_reports: bool,
 }
 
 impl GrpcClient {
-    pub fn new(grpc_server_config: &GrpcServerConfig) -> Result<Self> {
-        match Channel::from_shared(format!(
+    pub fn new(
+        grpc_server_config: &GrpcServerConfig,
+        queue_path: &Path,
+        inventory_path: &Path,
+        save_interval: Duration,
+        hash_cache: Arc<HashCache>,
+    ) -> Result<Self> {
+        let queue = EventQueue::open(queue_path)?;
+        let inventory = Inventory::load(inventory_path);
+        let endpoint = match Channel::from_shared(format!(
             "{}://{}:{}",
             grpc_server_config.protocol, grpc_server_config.host, grpc_server_config.port
         )) {
+            Ok(endpoint) => tls::configure_grpc_endpoint(
+                endpoint,
+                &grpc_server_config.protocol,
+                &grpc_server_config.tls,
+            ),
+            Err(e) => bail!(e),
+        };
+        match endpoint {
             Ok(endpoint) => Ok(Self {
                 client: None,
                 agent_uuid: None,
                 endpoint,
+                queue,
+                inventory,
+                stream: None,
+                in_flight: 0,
+                batch_size: grpc_server_config.batch_size.max(1),
+                flush_interval: Duration::from_millis(grpc_server_config.flush_interval_ms.max(1)),
+                save_interval,
+                backoff: Backoff::new(
+                    Duration::from_millis(grpc_server_config.reconnect_base_delay_ms),
+                    Duration::from_millis(grpc_server_config.reconnect_max_delay_ms),
+                ),
+                next_reconnect: Instant::now(),
+                status: Arc::new(Mutex::new(GrpcStatus::default())),
+                command_channel: None,
+                roots: WatchedRoots::default(),
+                watch_requests: None,
+                allowed_watch_roots: WatchedRoots::default(),
+                compression: match grpc_server_config.compression {
+                    GrpcCompression::None => None,
+                    GrpcCompression::Gzip => Some(CompressionEncoding::Gzip),
+                    GrpcCompression::Zstd => Some(CompressionEncoding::Zstd),
+                },
+                hash_cache,
+                scan_options: ScanOptions::default(),
+                scan_report: Arc::new(Mutex::new(None)),
+                send_scan_reports: grpc_server_config.send_scan_reports,
             }),
             Err(e) => bail!(e),
         }
@@ -83,6 +495,61 @@ impl GrpcClient {
         self.agent_uuid = Some(agent_uuid.clone());
     }
 
+    /// Shared view of the connection state, read by the HTTP server.
+    #[inline]
+    pub fn status(&self) -> Arc<Mutex<GrpcStatus>> {
+        self.status.clone()
+    }
+
+    /// Options of the scans run on the watched directories and of the changed files.
+    #[inline]
+    pub fn set_scan_options(&mut self, scan_options: ScanOptions) {
+        self.scan_options = scan_options;
+    }
+
+    /// Report of the last scan, read by the HTTP server.
+    #[inline]
+    pub fn scan_report(&self) -> Arc<Mutex<Option<ScanReport>>> {
+        self.scan_report.clone()
+    }
+
+    /// Keeps the report of a scan for the HTTP server, and sends it to the Hub when enabled.
+    /// A report the Hub could not receive is not sent again, the next scan has its own.
+    pub async fn publish_scan_report(&mut self, report: ScanReport) {
+        if self.send_scan_reports {
+            match &mut self.client {
+                Some(client) => {
+                    if let Err(err) = client.scan_report(proto_scan_report(&report)).await {
+                        warn!("Could not send the scan report to the Hub: {err}");
+                    }
+                }
+                None => debug!("Not connected to the Hub, the scan report is not sent"),
+            }
+        }
+        *self.scan_report.lock().unwrap() = Some(report);
+    }
+
+    /// Duplicate files among the known files, read by the HTTP server.
+    #[inline]
+    pub fn duplicates(&self) -> Arc<Mutex<DuplicateIndex>> {
+        self.inventory.duplicates()
+    }
+
+    fn set_last_acknowledged_sequence(&self, sequence: u64) {
+        let mut status = self.status.lock().unwrap();
+        status.last_acknowledged_sequence = status.last_acknowledged_sequence.max(sequence);
+    }
+
+    fn set_connection_state(&self, state: ConnectionState) {
+        let mut status = self.status.lock().unwrap();
+        status.state = state;
+        match state {
+            ConnectionState::Connected => status.reconnect_attempts = 0,
+            ConnectionState::Reconnecting => status.reconnect_attempts += 1,
+            ConnectionState::Disconnected => (),
+        }
+    }
+
     // Connect before setting interceptors !
     pub async fn connect(&mut self) -> Result<()> {
         ensure!(
@@ -99,39 +566,731 @@ impl GrpcClient {
         let interceptor = AuthInterceptor {
             agent_uuid: self.agent_uuid.clone().unwrap(),
         };
-        self.client = Some(TidyBeeEventsClient::with_interceptor(channel, interceptor));
+        let mut client = TidyBeeEventsClient::with_interceptor(channel, interceptor)
+            .accept_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Zstd);
+        if let Some(encoding) = self.compression {
+            client = client.send_compressed(encoding);
+        }
+        self.client = Some(client);
+        self.backoff.reset();
+        self.set_connection_state(ConnectionState::Connected);
+        self.open_command_channel();
+        if !self.queue.is_empty() {
+            if let Err(err) = self.flush().await {
+                warn!("Could not replay the undelivered events: {err}");
+            }
+        }
         Ok(())
     }
 
-    pub async fn send_create_events_once(
+    /// Drops the dead channel and schedules a reconnection, the queued events are kept and
+    /// replayed once the connection is back.
+    fn on_transport_failure(&mut self) {
+        self.client = None;
+        self.command_channel = None;
+        let delay = self.backoff.next_delay();
+        self.next_reconnect = Instant::now() + delay;
+        self.set_connection_state(ConnectionState::Reconnecting);
+        warn!("Lost connection to the gRPC server, reconnecting in {delay:?}");
+    }
+
+    async fn reconnect(&mut self) {
+        if let Err(err) = self.connect().await {
+            let delay = self.backoff.next_delay();
+            self.next_reconnect = Instant::now() + delay;
+            self.set_connection_state(ConnectionState::Reconnecting);
+            warn!("Failed to reconnect to the gRPC server: {err}, retrying in {delay:?}");
+        } else {
+            info!("Reconnected to the gRPC server");
+        }
+    }
+
+    // region: --- outbound queue
+
+    /// Persists the event in the outbound queue and records it in the inventory.
+    fn enqueue(&mut self, event: QueuedEvent) -> Result<(), GrpcClientError> {
+        self.queue.push(event.clone())?;
+        self.record_queued(&event);
+        Ok(())
+    }
+
+    fn enqueue_all(
         &mut self,
-        events: Vec<FileInfo>,
+        events: impl IntoIterator<Item = QueuedEvent>,
     ) -> Result<(), GrpcClientError> {
+        let events: Vec<QueuedEvent> = events.into_iter().collect();
+        self.queue.extend(events.iter().cloned())?;
+        for event in &events {
+            self.record_queued(event);
+        }
+        Ok(())
+    }
+
+    /// Keeps the inventory in line with a queued event.
+    fn record_queued(&mut self, event: &QueuedEvent) {
+        match event {
+            QueuedEvent::File(event) => self.inventory.record_file_event(event),
+            QueuedEvent::Folder(event) => self.inventory.record_folder_event(event),
+        }
+    }
+
+    /// Writes the inventory and the hash cache if they changed since they were last saved.
+    pub fn save_inventory(&mut self) {
+        if let Err(err) = self.inventory.save() {
+            warn!("Could not save the inventory: {err}");
+        }
+        if let Err(err) = self.hash_cache.save() {
+            warn!("Could not save the hash cache: {err}");
+        }
+    }
+
+    /// Persists the event in the outbound queue, then streams it to the Hub.
+    /// A delivery failure is not fatal: the event stays queued until the next successful flush.
+    async fn dispatch(&mut self, event: QueuedEvent) -> Result<(), GrpcClientError> {
+        self.enqueue(event)?;
+        if let Err(err) = self.stream_pending().await {
+            warn!("{err}, {} events kept in the queue", self.queue.len());
+        }
+        Ok(())
+    }
+
+    /// Writes the queued events that are not in flight yet to the open stream, opening a new one
+    /// when needed. A stream only carries events of a single kind so that the Hub receives them in
+    /// order, and it is closed (thus acknowledged) once it carried `batch_size` events.
+    async fn stream_pending(&mut self) -> Result<(), GrpcClientError> {
+        if self.client.is_none() {
+            // Events stay queued until the connection is back
+            return Ok(());
+        }
+        while let Some(event) = self.queue.get(self.in_flight)?.cloned() {
+            let reusable = match &self.stream {
+                Some(stream) => stream.accepts(&event) && stream.sent < self.batch_size,
+                None => true,
+            };
+            if !reusable {
+                self.close_stream().await?;
+            }
+            if self.stream.is_none() {
+                self.stream = Some(self.open_stream(&event)?);
+            }
+            if let Some(stream) = self.stream.as_mut() {
+                if stream.send(event).await.is_err() {
+                    // The RPC ended early, its status is reported when closing the stream
+                    return self.close_stream().await;
+                }
+            }
+            self.in_flight += 1;
+        }
+        match &self.stream {
+            Some(stream) if stream.sent >= self.batch_size => self.close_stream().await,
+            _ => Ok(()),
+        }
+    }
+
+    fn open_stream(&self, first_event: &QueuedEvent) -> Result<EventStream, GrpcClientError> {
+        let mut client = match self.client.clone() {
+            Some(client) => client,
+            None => return Err(GrpcClientError::ClientNotConnected()),
+        };
+        let stream = match first_event {
+            QueuedEvent::File(_) => {
+                let (sender, receiver) = mpsc::channel(self.batch_size);
+                EventStream {
+                    sender: StreamSender::File(sender),
+                    response: tokio::spawn(async move {
+                        client
+                            .file_event(ReceiverStream::new(receiver))
+                            .await
+                            .map(Response::into_inner)
+                    }),
+                    sent: 0,
+                    last_sent: Instant::now(),
+                }
+            }
+            QueuedEvent::Folder(_) => {
+                let (sender, receiver) = mpsc::channel(self.batch_size);
+                EventStream {
+                    sender: StreamSender::Folder(sender),
+                    response: tokio::spawn(async move {
+                        client
+                            .folder_event(ReceiverStream::new(receiver))
+                            .await
+                            .map(Response::into_inner)
+                    }),
+                    sent: 0,
+                    last_sent: Instant::now(),
+                }
+            }
+        };
+        debug!("Opened a new event stream to the gRPC server");
+        Ok(stream)
+    }
+
+    /// Ends the open stream and waits for the Hub response, removing the events it carried from
+    /// the queue when the Hub acknowledged them.
+    async fn close_stream(&mut self) -> Result<(), GrpcClientError> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let in_flight = std::mem::take(&mut self.in_flight);
+        let response = stream.finish().await;
+
+        match response {
+            Ok(response) if response.status == EventStatus::Ok as i32 => {
+                let last_in_flight = match in_flight.checked_sub(1) {
+                    Some(last) => self.queue.get(last)?.map_or(0, QueuedEvent::sequence),
+                    None => 0,
+                };
+                let acknowledged_sequence = match response.acknowledged_sequence {
+                    // Hubs unaware of sequence numbers acknowledge the whole stream
+                    0 => last_in_flight,
+                    // Events queued after the stream must not be dropped unsent
+                    sequence if sequence > last_in_flight => {
+                        warn!(
+                            "Hub acknowledged sequence {sequence} but the stream ended at {last_in_flight}"
+                        );
+                        last_in_flight
+                    }
+                    sequence => sequence,
+                };
+                debug!("Hub acknowledged events up to sequence {acknowledged_sequence}");
+                self.queue.acknowledge_up_to(acknowledged_sequence)?;
+                self.set_last_acknowledged_sequence(acknowledged_sequence);
+                Ok(())
+            }
+            Ok(_) => {
+                warn!("Hub refused the events");
+                Err(GrpcClientError::EventSendError())
+            }
+            Err(status) => {
+                warn!("Failed to send events to gRPC server: {}", status.message());
+                if is_transport_failure(&status) {
+                    self.on_transport_failure();
+                }
+                Err(GrpcClientError::EventSendError())
+            }
+        }
+    }
+
+    /// Sends everything that is still queued and waits for the Hub acknowledgement.
+    pub async fn flush(&mut self) -> Result<(), GrpcClientError> {
+        self.stream_pending().await?;
+        self.close_stream().await
+    }
+
+    /// Called periodically: closes the stream once it has been idle for `flush_interval`,
+    /// reconnects when the backoff delay is over and retries the events left in the queue by a
+    /// previous failure.
+    async fn on_flush_tick(&mut self) -> Result<(), GrpcClientError> {
         if self.client.is_none() {
-            return Err(GrpcClientError::ClientNotConnected());
-        }
-        let stream = tokio_stream::iter(events.into_iter().map(|f| FileEventRequest {
-            event_type: FileEventType::Created as i32,
-            pretty_path: f.pretty_path.display().to_string(),
-            path: vec![f.path.display().to_string()],
-            size: Some(f.size),
-            hash: f.hash,
-            last_accessed: Some(f.last_accessed.into()),
-            last_modified: Some(f.last_modified.into()),
-        }));
-        let _ = self.client.as_mut().unwrap().file_event(stream).await;
+            if Instant::now() >= self.next_reconnect {
+                self.reconnect().await;
+            }
+            return Ok(());
+        }
+        match &self.stream {
+            Some(stream) if stream.last_sent.elapsed() >= self.flush_interval => self.flush().await,
+            Some(_) => Ok(()),
+            None if self.queue.is_empty() => Ok(()),
+            None => self.flush().await,
+        }
+    }
+
+    // endregion: --- outbound queue
+
+    // region: --- Hub commands
+
+    /// Directories the agent watches, Hub commands are only allowed to touch paths inside them.
+    /// New directories requested by the Hub are forwarded to the watcher thread, they must be
+    /// inside the watched directories or the allowed ones.
+    pub fn attach_watcher(
+        &mut self,
+        watched_directories: Vec<PathBuf>,
+        allowed_watch_roots: &[PathBuf],
+        watch_requests: std::sync::mpsc::Sender<PathBuf>,
+    ) {
+        self.roots = WatchedRoots::new(&watched_directories);
+        self.allowed_watch_roots = WatchedRoots::new(&watched_directories);
+        for directory in allowed_watch_roots {
+            self.allowed_watch_roots.add(directory);
+        }
+        self.watch_requests = Some(watch_requests);
+    }
+
+    /// Opens the command channel, commands are received by a background task and handled by
+    /// the `send_events` loop so that their events go through the outbound queue.
+    fn open_command_channel(&mut self) {
+        let mut client = match self.client.clone() {
+            Some(client) => client,
+            None => return,
+        };
+        let (result_sender, result_receiver) = mpsc::channel(COMMAND_CHANNEL_CAPACITY);
+        let (command_sender, command_receiver) = mpsc::channel(COMMAND_CHANNEL_CAPACITY);
+
+        tokio::spawn(async move {
+            let mut commands = match client
+                .command_channel(ReceiverStream::new(result_receiver))
+                .await
+            {
+                Ok(response) => response.into_inner(),
+                Err(status) if status.code() == Code::Unimplemented => {
+                    info!("The Hub does not support the command channel");
+                    return;
+                }
+                Err(status) => {
+                    warn!("Failed to open the command channel: {}", status.message());
+                    return;
+                }
+            };
+            loop {
+                match commands.message().await {
+                    Ok(Some(command)) => {
+                        if command_sender.send(command).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(status) => {
+                        warn!("Command channel closed: {}", status.message());
+                        break;
+                    }
+                }
+            }
+        });
+
+        self.command_channel = Some(CommandChannel {
+            commands: command_receiver,
+            results: result_sender,
+        });
+    }
+
+    async fn handle_command(&mut self, command: HubCommand) {
+        debug!("Received command from the Hub: {:?}", command);
+        let command_id = command.command_id;
+        let mut agent_data = None;
+        let result = match command.command {
+            Some(Command::Rescan(rescan)) => self.rescan(Path::new(&rescan.path)).await,
+            Some(Command::Rehash(rehash)) => self.rehash(Path::new(&rehash.path)).await,
+            Some(Command::Watch(watch)) => self.watch(Path::new(&watch.path)).await,
+            Some(Command::Status(_)) => {
+                agent_data = Some(self.agent_data());
+                let status = self.status.lock().unwrap().clone();
+                Ok(format!(
+                    "{} events pending, last acknowledged sequence {}",
+                    self.queue.len(),
+                    status.last_acknowledged_sequence
+                ))
+            }
+            None => Err(GrpcClientError::InvalidCommand(String::from(
+                "empty command",
+            ))),
+        };
+
+        let result = match result {
+            Ok(message) => CommandResult {
+                command_id,
+                status: EventStatus::Ok as i32,
+                message: Some(message),
+                agent_data,
+            },
+            Err(err) => {
+                warn!("Command {command_id} failed: {err}");
+                CommandResult {
+                    command_id,
+                    status: EventStatus::Error as i32,
+                    message: Some(err.to_string()),
+                    agent_data,
+                }
+            }
+        };
+        if let Some(channel) = &self.command_channel {
+            if channel.results.send(result).await.is_err() {
+                warn!("Could not send the result of command {command_id} to the Hub");
+            }
+        }
+    }
+
+    /// Resolves a path sent by the Hub, rejecting anything outside of the watched directories.
+    fn resolve_command_path(&self, path: &Path) -> Result<PathBuf, GrpcClientError> {
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                return Err(GrpcClientError::InvalidCommand(format!(
+                    "{}: {e}",
+                    path.display()
+                )))
+            }
+        };
+        if self.roots.contains(&resolved) {
+            Ok(resolved)
+        } else {
+            Err(GrpcClientError::InvalidCommand(format!(
+                "{} is not inside a watched directory",
+                path.display()
+            )))
+        }
+    }
+
+    fn created_events(&self, files: Vec<FileInfo>) -> Vec<QueuedEvent> {
+        files
+            .into_iter()
+            .map(|info| {
+                QueuedEvent::File(file_info_event(FileEventType::Created, info, &self.roots))
+            })
+            .collect()
+    }
+
+    /// Describes a file on the blocking pool, so that hashing does not hold a runtime thread.
+    async fn describe_file(&self, path: &Path) -> Option<FileInfo> {
+        let hash_cache = self.hash_cache.clone();
+        let follow_symlinks = self.scan_options.follow_symlinks;
+        let file = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            file_info::create_file_info(&file, &hash_cache, follow_symlinks)
+        })
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Could not describe {}: {err}", path.display());
+            None
+        })
+    }
+
+    /// Events of a file found on disk, followed when it is an archive by the ones of its entries:
+    /// the entries it contains, and the deletion of the entries known to the Hub it no longer has.
+    async fn file_events(&self, event_type: FileEventType, info: FileInfo) -> Vec<QueuedEvent> {
+        let path = info.path.clone();
+        let files =
+            if self.scan_options.archive_introspection && info.category == FileCategory::Archive {
+                // Reading the archive blocks, and may take long
+                let hash_cache = self.hash_cache.clone();
+                let limits = self.scan_options.archive_limits;
+                let archive = info.clone();
+                tokio::task::spawn_blocking(move || {
+                    archive::with_entries(archive, &hash_cache, &limits)
+                })
+                .await
+                .unwrap_or_else(|err| {
+                    warn!("Could not list the entries of {}: {err}", path.display());
+                    vec![info]
+                })
+            } else {
+                vec![info]
+            };
+        let listed: HashSet<&Path> = files
+            .iter()
+            .filter_map(|file| file.inner_path.as_deref())
+            .collect();
+        let stale: Vec<QueuedEvent> = self
+            .inventory
+            .entries_of(&path)
+            .into_iter()
+            .filter(|inner_path| !listed.contains(inner_path.as_path()))
+            .map(|inner_path| {
+                QueuedEvent::File(deleted_event(&path, Some(&inner_path), &self.roots))
+            })
+            .collect();
+        files
+            .into_iter()
+            .map(|info| QueuedEvent::File(file_info_event(event_type, info, &self.roots)))
+            .chain(stale)
+            .collect()
+    }
+
+    /// Deletion of a file, preceded by the deletion of its entries when it is an archive.
+    fn deleted_events(&self, path: &Path) -> Vec<QueuedEvent> {
+        self.inventory
+            .entries_of(path)
+            .into_iter()
+            .map(|inner_path| {
+                QueuedEvent::File(deleted_event(path, Some(&inner_path), &self.roots))
+            })
+            .chain([QueuedEvent::File(deleted_event(path, None, &self.roots))])
+            .collect()
+    }
+
+    /// Persists the events in the outbound queue, then streams them to the Hub.
+    async fn dispatch_all(&mut self, events: Vec<QueuedEvent>) -> Result<(), GrpcClientError> {
+        self.enqueue_all(events)?;
+        if let Err(err) = self.stream_pending().await {
+            warn!("{err}, {} events kept in the queue", self.queue.len());
+        }
         Ok(())
     }
 
+    /// Queues the events of the files of a scan batch by batch as the scan finds them, streaming
+    /// them to the Hub meanwhile, then publishes the scan report. Returns the number of files
+    /// found and the paths the scan could not read.
+    async fn queue_scan(
+        &mut self,
+        mut scan: FileScan,
+        mut events_of: impl FnMut(&Self, Vec<FileInfo>) -> Vec<QueuedEvent>,
+    ) -> Result<(usize, SkippedPaths), GrpcClientError> {
+        while let Some(files) = scan.next_batch().await {
+            let events = events_of(self, files);
+            if !events.is_empty() {
+                self.dispatch_all(events).await?;
+            }
+        }
+        let mut report = scan.finish().await.map_err(GrpcClientError::ScanError)?;
+        let files_found = report.files_found;
+        let skipped = std::mem::take(&mut report.skipped_paths);
+        self.publish_scan_report(report).await;
+        Ok((files_found, skipped))
+    }
+
+    /// Queues the creation of the files of a directory moved into a watched one.
+    async fn queue_directory(&mut self, directory: &Path) -> Result<(), GrpcClientError> {
+        let scan = file_lister::stream_directories(
+            vec![directory.to_path_buf()],
+            self.hash_cache.clone(),
+            self.scan_options.clone(),
+        );
+        self.queue_scan(scan, |client, files| client.created_events(files))
+            .await?;
+        Ok(())
+    }
+
+    async fn rescan(&mut self, path: &Path) -> Result<String, GrpcClientError> {
+        let directory = self.resolve_command_path(path)?;
+        if !directory.is_dir() {
+            return Err(GrpcClientError::InvalidCommand(format!(
+                "{} is not a directory",
+                path.display()
+            )));
+        }
+        // Covering every watched directory, the scan looks up every file the cache should keep
+        let complete = self
+            .roots
+            .iter()
+            .all(|root| root.path.starts_with(&directory));
+        let scan = file_lister::stream_directories(
+            vec![directory],
+            self.hash_cache.clone(),
+            self.scan_options.clone(),
+        );
+        let generation = scan.cache_generation();
+        let (count, skipped) = self
+            .queue_scan(scan, |client, files| client.created_events(files))
+            .await?;
+        if complete && skipped.is_empty() {
+            self.hash_cache.prune(generation);
+        }
+        Ok(format!("{count} files rescanned"))
+    }
+
+    async fn rehash(&mut self, path: &Path) -> Result<String, GrpcClientError> {
+        let file = self.resolve_command_path(path)?;
+        // The cache would return the hash the Hub doubts, read the content again
+        if let Ok(md) = fs::metadata(&file) {
+            if md.is_file() {
+                let hash_cache = self.hash_cache.clone();
+                let path = file.clone();
+                let refreshed = tokio::task::spawn_blocking(move || {
+                    hash_cache
+                        .refresh(&path, &md)
+                        .map_err(|err| warn!("{err}"))
+                        .is_ok()
+                })
+                .await
+                .unwrap_or(false);
+                if !refreshed {
+                    return Err(GrpcClientError::FileInfoError());
+                }
+            }
+        }
+        let info = match self.describe_file(&file).await {
+            Some(info) => info,
+            None => return Err(GrpcClientError::FileInfoError()),
+        };
+        let hash = info.hash.clone().unwrap_or_default();
+        self.dispatch_all(self.file_events(FileEventType::Updated, info).await)
+            .await?;
+        Ok(hash)
+    }
+
+    async fn watch(&mut self, path: &Path) -> Result<String, GrpcClientError> {
+        let directory = match path.canonicalize() {
+            Ok(directory) if directory.is_dir() => directory,
+            _ => {
+                return Err(GrpcClientError::InvalidCommand(format!(
+                    "{} is not a directory",
+                    path.display()
+                )))
+            }
+        };
+        if !self.allowed_watch_roots.contains(&directory) {
+            return Err(GrpcClientError::InvalidCommand(format!(
+                "{} is not inside a watched or allowed directory",
+                path.display()
+            )));
+        }
+        let watch_requests = match &self.watch_requests {
+            Some(watch_requests) => watch_requests,
+            None => {
+                return Err(GrpcClientError::InvalidCommand(String::from(
+                    "the file watcher is not running",
+                )))
+            }
+        };
+        // The rules must apply to the first events of the directory
+        self.scan_options.ignore_rules.add_root(&directory);
+        if watch_requests.send(directory.clone()).is_err() {
+            return Err(GrpcClientError::InvalidCommand(String::from(
+                "the file watcher stopped",
+            )));
+        }
+        self.roots.add(&directory);
+        self.rescan(&directory).await
+    }
+
+    fn agent_data(&self) -> AgentData {
+        AgentData {
+            agent_version: String::from(env!("CARGO_PKG_VERSION")),
+            machine_name: gethostname().to_string_lossy().into_owned(),
+            process_id: std::process::id(),
+            uptime: sysinfo::System::uptime(),
+            watched_directories: self
+                .roots
+                .iter()
+                .map(|root| root.path.display().to_string())
+                .collect(),
+            watched_roots: self
+                .roots
+                .iter()
+                .map(|root| ProtoWatchedRoot {
+                    id: root.id.clone(),
+                    path: root.path.display().to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    // endregion: --- Hub commands
+
+    /// Sends every file of the startup scan as created as soon as it is found, replacing the
+    /// inventory of the previous run.
+    pub async fn send_create_events_once(&mut self, scan: FileScan) -> Result<(), GrpcClientError> {
+        self.inventory.clear();
+        let generation = scan.cache_generation();
+        let (_, skipped) = self
+            .queue_scan(scan, |client, files| client.created_events(files))
+            .await?;
+        if skipped.is_empty() {
+            // Every file that still exists was looked up, the other entries are stale
+            self.hash_cache.prune(generation);
+        }
+        self.save_inventory();
+        self.flush().await
+    }
+
+    /// Scans every watched directory again and sends what changed since the inventory.
+    async fn resync(&mut self) -> Result<(), GrpcClientError> {
+        let directories: Vec<PathBuf> = self.roots.iter().map(|root| root.path.clone()).collect();
+        let scan = file_lister::stream_directories(
+            directories.clone(),
+            self.hash_cache.clone(),
+            self.scan_options.clone(),
+        );
+        self.send_reconciled_events(&directories, scan).await
+    }
+
+    /// Sends only what changed in `directories` since the inventory of the previous run, the
+    /// changed files as soon as the startup scan finds them, then the files deleted while the
+    /// agent was not running once the scan is over.
+    pub async fn send_reconciled_events(
+        &mut self,
+        directories: &[PathBuf],
+        scan: FileScan,
+    ) -> Result<(), GrpcClientError> {
+        let roots: Vec<PathBuf> = directories
+            .iter()
+            .filter_map(|directory| directory.canonicalize().ok())
+            .map(file_info::fix_canonicalize_path)
+            .collect();
+        let known = self.inventory.len();
+        let mut reconciliation = Reconciliation::new(&roots);
+        let generation = scan.cache_generation();
+        let (_, skipped) = self
+            .queue_scan(scan, |client, files| {
+                files
+                    .into_iter()
+                    .filter_map(|info| {
+                        let event_type = match reconciliation.compare(&client.inventory, &info) {
+                            FileChange::Created => FileEventType::Created,
+                            FileChange::Updated => FileEventType::Updated,
+                            FileChange::Unchanged => return None,
+                        };
+                        Some(QueuedEvent::File(file_info_event(
+                            event_type,
+                            info,
+                            &client.roots,
+                        )))
+                    })
+                    .collect()
+            })
+            .await?;
+
+        let (created, updated) = (reconciliation.created, reconciliation.updated);
+        let deleted: Vec<QueuedEvent> = reconciliation
+            .deleted(&self.inventory, &skipped)
+            .into_iter()
+            .map(|(path, inner_path)| {
+                QueuedEvent::File(deleted_event(&path, inner_path.as_deref(), &self.roots))
+            })
+            .collect();
+        info!(
+            "Reconciliation against {known} known files: {created} created, {updated} updated, {} deleted",
+            deleted.len()
+        );
+        self.enqueue_all(deleted)?;
+        if skipped.is_empty() {
+            // Every file that still exists was looked up, the other entries are stale
+            self.hash_cache.prune(generation);
+        } else {
+            warn!("The known files under the paths the scan could not read are kept");
+        }
+        self.save_inventory();
+        self.flush().await
+    }
+
     pub async fn send_events(
         &mut self,
-        mut file_watcher_receiver: UnboundedReceiver<DebouncedEvent>,
+        mut file_watcher_receiver: EventReceiver,
     ) -> Result<(), Error> {
         if self.client.is_none() {
             bail!(GrpcClientError::ClientNotConnected());
         }
 
-        while let Some(file_event) = file_watcher_receiver.recv().await {
+        let mut flush_ticker = time::interval(self.flush_interval);
+        // Rewriting the inventory and the hash cache is too costly for every flush
+        let mut save_ticker = time::interval(self.save_interval);
+        save_ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            let file_event = tokio::select! {
+                file_event = file_watcher_receiver.recv() => match file_event {
+                    Some(file_event) => file_event,
+                    None => break,
+                },
+                command = next_command(&mut self.command_channel) => {
+                    match command {
+                        Some(command) => self.handle_command(command).await,
+                        None => self.command_channel = None,
+                    }
+                    continue;
+                }
+                _ = flush_ticker.tick() => {
+                    if let Err(err) = self.on_flush_tick().await {
+                        warn!("{err}, {} events kept in the queue", self.queue.len());
+                    }
+                    continue;
+                }
+                _ = save_ticker.tick() => {
+                    self.save_inventory();
+                    continue;
+                }
+            };
             if file_event.kind
                 == notify::event::EventKind::Access(notify::event::AccessKind::Open(
                     notify::event::AccessMode::Any,
@@ -139,234 +1298,133 @@ impl GrpcClient {
             {
                 continue;
             }
-            println!("{:?}", file_event);
-            match file_event.kind {
+            if file_event.need_rescan() {
+                // The watcher dropped events, only a scan can tell what changed meanwhile
+                warn!("The file watcher missed events, reconciling the watched directories");
+                if let Err(err) = self.resync().await {
+                    warn!("Could not reconcile the watched directories: {err}");
+                }
+                continue;
+            }
+            let (path, destination) = match file_event.paths.as_slice() {
+                [path, destination, ..] => (path.clone(), Some(destination.clone())),
+                [path] => (path.clone(), None),
+                [] => {
+                    debug!("Skipping an event without path: {:?}", file_event);
+                    continue;
+                }
+            };
+            if let Some(root) = self.roots.root_of(&path) {
+                // The watcher follows the links to directories whatever the scan options
+                if !self.scan_options.reaches(&path, &root.path) {
+                    continue;
+                }
+            }
+            debug!("{:?}", file_event);
+            let result = match file_event.kind {
                 notify::EventKind::Create(notify::event::CreateKind::File) => {
-                    let info = match file_info::create_file_info(&file_event.paths[0].clone()) {
-                        Some(info) => info,
+                    match self.describe_file(&path).await {
+                        Some(info) => {
+                            self.dispatch_all(self.file_events(FileEventType::Created, info).await)
+                                .await
+                        }
                         None => continue,
-                    };
-                    let event = FileEventRequest {
-                        event_type: FileEventType::Created as i32,
-                        pretty_path: info.pretty_path.display().to_string(),
-                        path: vec![info.path.display().to_string()],
-                        size: Some(info.size),
-                        hash: info.hash,
-                        last_accessed: Some(info.last_accessed.into()),
-                        last_modified: Some(info.last_modified.into()),
-                    };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .file_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
                     }
                 }
                 notify::EventKind::Modify(modify_kind) => {
-                    self.handle_modify_events(modify_kind, file_event).await?
+                    self.handle_modify_events(modify_kind, &path, destination.as_deref())
+                        .await
                 }
                 notify::EventKind::Remove(remove_kind) => {
-                    self.handle_remove_events(remove_kind, file_event).await?
+                    self.handle_remove_events(remove_kind, &path).await
                 }
-                _ => (),
+                _ => Ok(()),
             };
+            if let Err(err) = result {
+                warn!("Could not handle file event: {err}");
+            }
         }
 
+        self.save_inventory();
+        self.flush().await?;
         Ok(())
     }
 
     // region: --- event handlers
 
+    /// `destination` is the new path of a renamed file or folder, the other events have a single
+    /// path.
     async fn handle_modify_events(
         &mut self,
         modify_kind: notify::event::ModifyKind,
-        file_event: DebouncedEvent,
-    ) -> Result<(), Error> {
+        path: &Path,
+        destination: Option<&Path>,
+    ) -> Result<(), GrpcClientError> {
         match modify_kind {
             ModifyKind::Data(_) => {
-                let info = match file_info::create_file_info(&file_event.paths[0].clone()) {
+                let info = match self.describe_file(path).await {
                     Some(info) => info,
-                    None => bail!(GrpcClientError::FileInfoError()),
-                };
-                let event = FileEventRequest {
-                    event_type: FileEventType::Created as i32,
-                    pretty_path: info.pretty_path.display().to_string(),
-                    path: vec![info.path.display().to_string()],
-                    size: Some(info.size),
-                    hash: info.hash,
-                    last_accessed: Some(info.last_accessed.into()),
-                    last_modified: Some(info.last_modified.into()),
+                    None => return Err(GrpcClientError::FileInfoError()),
                 };
-                if self
-                    .client
-                    .as_mut()
-                    .unwrap()
-                    .file_event(tokio_stream::iter(vec![event]))
-                    .await
-                    .is_err()
-                {
-                    warn!("Failed to send file event to gRPC server");
-                    bail!(GrpcClientError::EventSendError());
-                }
+                self.dispatch_all(self.file_events(FileEventType::Updated, info).await)
+                    .await?;
             }
             // The ModifyKind::Name documentation is a bit unprecise, notify::event::RenameMode::To represent a new file or folder that was moved in the scope of the watcher
             ModifyKind::Name(notify::event::RenameMode::To) => {
-                if file_event.paths[0].is_dir() {
-                    match file_lister::list_directories(vec![file_event.paths[0].clone()]) {
-                        Ok(file_info_vec) => {
-                            let events = file_info_vec.into_iter().map(|f| FileEventRequest {
-                                event_type: FileEventType::Created as i32,
-                                pretty_path: f.pretty_path.display().to_string(),
-                                path: vec![f.path.display().to_string()],
-                                size: Some(f.size),
-                                hash: f.hash,
-                                last_accessed: Some(f.last_accessed.into()),
-                                last_modified: Some(f.last_modified.into()),
-                            });
-                            if self
-                                .client
-                                .as_mut()
-                                .unwrap()
-                                .file_event(tokio_stream::iter(events))
-                                .await
-                                .is_err()
-                            {
-                                warn!("Failed to send file event to gRPC server");
-                                bail!(GrpcClientError::EventSendError());
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Failed to list directory: {:?}", e);
-                            bail!(GrpcClientError::FileInfoError());
-                        }
-                    }
+                if self.scan_options.is_scanned_directory(path) {
+                    self.queue_directory(path).await?;
                 } else {
-                    let info = match file_info::create_file_info(&file_event.paths[1].clone()) {
+                    let info = match self.describe_file(path).await {
                         Some(info) => info,
-                        None => bail!(GrpcClientError::FileInfoError()),
-                    };
-                    let event = FileEventRequest {
-                        event_type: FileEventType::Created as i32,
-                        pretty_path: info.pretty_path.display().to_string(),
-                        path: vec![info.path.display().to_string()],
-                        size: Some(info.size),
-                        hash: info.hash,
-                        last_accessed: Some(info.last_accessed.into()),
-                        last_modified: Some(info.last_modified.into()),
+                        None => return Err(GrpcClientError::FileInfoError()),
                     };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .file_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+                    self.dispatch_all(self.file_events(FileEventType::Created, info).await)
+                        .await?;
                 }
             }
             // The ModifyKind::Name documentation is a bit unprecise, notify::event::RenameMode::From represent a file or folder that was moved out of the scope of the watcher
             // Thus files associated with this event should be deleted from the database
             ModifyKind::Name(notify::event::RenameMode::From) => {
-                if file_event.paths[0].is_dir() {
-                    let event = FolderEventRequest {
-                        event_type: FileEventType::Deleted as i32,
-                        old_path: file_event.paths[0].display().to_string(),
-                        new_path: None,
-                    };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .folder_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+                if self.scan_options.is_scanned_directory(path) {
+                    let event = folder_event(FileEventType::Deleted, path, None, &self.roots);
+                    self.dispatch(QueuedEvent::Folder(event)).await?;
                 } else {
-                    let event = FileEventRequest {
-                        event_type: FileEventType::Deleted as i32,
-                        pretty_path: file_event.paths[0].display().to_string(),
-                        path: vec![file_event.paths[0].display().to_string()],
-                        size: None,
-                        hash: None,
-                        last_accessed: None,
-                        last_modified: None,
-                    };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .file_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+                    self.dispatch_all(self.deleted_events(path)).await?;
                 }
             }
             // In this case, the object was actually renamed, so we can use the Moved event type
             ModifyKind::Name(notify::event::RenameMode::Both) => {
-                if file_event.paths[0].is_dir() {
-                    let event = FolderEventRequest {
-                        event_type: FileEventType::Moved as i32,
-                        old_path: file_event.paths[0].display().to_string(),
-                        new_path: Some(file_event.paths[1].display().to_string()),
-                    };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .folder_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
+                let Some(destination) = destination else {
+                    debug!(
+                        "Skipping the rename of {} without destination",
+                        path.display()
+                    );
+                    return Ok(());
+                };
+                if self.scan_options.is_scanned_directory(destination) {
+                    if self.roots.root_of(path) == self.roots.root_of(destination) {
+                        let event = folder_event(
+                            FileEventType::Moved,
+                            path,
+                            Some(destination),
+                            &self.roots,
+                        );
+                        self.dispatch(QueuedEvent::Folder(event)).await?;
+                    } else {
+                        // Pretty paths are relative to a watched directory, so a folder moved to
+                        // another one is removed from the first and its files created in the other
+                        let event = folder_event(FileEventType::Deleted, path, None, &self.roots);
+                        self.dispatch(QueuedEvent::Folder(event)).await?;
+                        self.queue_directory(destination).await?;
                     }
                 } else {
-                    let info = match file_info::create_file_info(&file_event.paths[1].clone()) {
+                    let info = match self.describe_file(destination).await {
                         Some(info) => info,
-                        None => bail!(GrpcClientError::FileInfoError()),
+                        None => return Err(GrpcClientError::FileInfoError()),
                     };
-                    let event = vec![FileEventRequest {
-                        event_type: FileEventType::Created as i32,
-                        pretty_path: info.pretty_path.display().to_string(),
-                        path: vec![info.path.display().to_string()],
-                        size: Some(info.size),
-                        hash: info.hash,
-                        last_accessed: Some(info.last_accessed.into()),
-                        last_modified: Some(info.last_modified.into()),
-                    },
-                    FileEventRequest {
-                        event_type: FileEventType::Deleted as i32,
-                        pretty_path: &file_event.paths[0].clone(),
-                        path: vec![&file_event.paths[0].clone()],
-                        size: None,
-                        hash: None,
-                        last_accessed: None,
-                        last_modified: None,
-                    }];
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .file_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+                    let mut events = self.deleted_events(path);
+                    events.extend(self.file_events(FileEventType::Created, info).await);
+                    self.dispatch_all(events).await?;
                 }
             }
             _ => (),
@@ -377,54 +1435,79 @@ impl GrpcClient {
     async fn handle_remove_events(
         &mut self,
         remove_kind: notify::event::RemoveKind,
-        file_event: DebouncedEvent,
-    ) -> Result<(), Error> {
+        path: &Path,
+    ) -> Result<(), GrpcClientError> {
         match remove_kind {
-            notify::event::RemoveKind::File => {
-                let event = FileEventRequest {
-                    event_type: FileEventType::Deleted as i32,
-                    pretty_path: file_event.paths[0].display().to_string(),
-                    path: vec![file_event.paths[0].display().to_string()],
-                    size: None,
-                    hash: None,
-                    last_accessed: None,
-                    last_modified: None,
-                };
-                if self
-                    .client
-                    .as_mut()
-                    .unwrap()
-                    .file_event(tokio_stream::iter(vec![event]))
-                    .await
-                    .is_err()
-                {
-                    warn!("Failed to send file event to gRPC server");
-                    bail!(GrpcClientError::EventSendError());
-                }
-
-                Ok(())
-            }
+            notify::event::RemoveKind::File => self.dispatch_all(self.deleted_events(path)).await,
             notify::event::RemoveKind::Folder => {
-                let event = FolderEventRequest {
-                    event_type: FileEventType::Deleted as i32,
-                    old_path: file_event.paths[0].display().to_string(),
-                    new_path: None,
-                };
-                if self
-                    .client
-                    .as_mut()
-                    .unwrap()
-                    .folder_event(tokio_stream::iter(vec![event]))
-                    .await
-                    .is_err()
-                {
-                    warn!("Failed to send file event to gRPC server");
-                    bail!(GrpcClientError::EventSendError());
-                }
-                Ok(())
+                let event = folder_event(FileEventType::Deleted, path, None, &self.roots);
+                self.dispatch(QueuedEvent::Folder(event)).await
             }
             _ => Ok(()),
         }
     }
     // endregion: --- event handlers
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_stays_capped() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+
+        let first = backoff.next_delay();
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        for _ in 0..10 {
+            let delay = backoff.next_delay();
+            assert!(delay <= Duration::from_millis(1000));
+        }
+        let capped = backoff.next_delay();
+        assert!(capped >= Duration::from_millis(500));
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn only_allowed_directories_are_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        let (watched, allowed, other) = (
+            dir.path().join("watched"),
+            dir.path().join("allowed"),
+            dir.path().join("other"),
+        );
+        for directory in [&watched, &allowed.join("sub"), &other] {
+            fs::create_dir_all(directory).unwrap();
+        }
+        let mut client = GrpcClient::new(
+            &crate::configuration::Configuration::default()
+                .hub_config
+                .grpc_server,
+            &dir.path().join("state/outbound_events.wal"),
+            &dir.path().join("state/inventory.json"),
+            Duration::from_secs(30),
+            Arc::new(HashCache::default()),
+        )
+        .unwrap();
+        let (watch_requests, requested) = std::sync::mpsc::channel();
+        client.attach_watcher(
+            vec![watched],
+            std::slice::from_ref(&allowed),
+            watch_requests,
+        );
+
+        assert!(matches!(
+            client.watch(&other).await,
+            Err(GrpcClientError::InvalidCommand(_))
+        ));
+        assert!(requested.try_recv().is_err());
+
+        let _ = client.watch(&allowed.join("sub")).await;
+        assert_eq!(
+            requested.try_recv().unwrap(),
+            allowed.join("sub").canonicalize().unwrap()
+        );
+    }
+}
@@ -1,11 +1,15 @@
 use crate::agent_uuid;
-use crate::configuration::HubConfig;
+use crate::configuration::{HubConfig, StateConfig};
 use crate::error::HubError::*;
+use crate::hash_cache::HashCache;
 use crate::http::grpc::GrpcClient;
+use crate::http::tls;
 use anyhow::{bail, Error};
 use gethostname::gethostname;
 use reqwest::header::CONTENT_TYPE;
 use reqwest::Client;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::{error, info};
 
 pub struct Hub {
@@ -15,9 +19,25 @@ pub struct Hub {
 }
 
 impl Hub {
-    pub fn new(hub_config: HubConfig) -> Result<Self, Error> {
-        let http_client: Client = Client::new();
-        let grpc_client = match GrpcClient::new(&hub_config.grpc_server) {
+    pub fn new(
+        hub_config: HubConfig,
+        state_config: &StateConfig,
+        hash_cache: Arc<HashCache>,
+    ) -> Result<Self, Error> {
+        let http_client: Client = tls::configure_http_client(
+            Client::builder(),
+            &hub_config.host,
+            &hub_config.port,
+            &hub_config.tls,
+        )?
+        .build()?;
+        let grpc_client = match GrpcClient::new(
+            &hub_config.grpc_server,
+            &state_config.dir.join("outbound_events.wal"),
+            &state_config.dir.join("inventory.json"),
+            Duration::from_millis(state_config.save_interval_ms.max(1)),
+            hash_cache,
+        ) {
             Ok(client) => client,
             Err(e) => {
                 bail!(HubClientCreationFailed(e.to_string()))
@@ -32,10 +52,14 @@ impl Hub {
 
     pub async fn connect(&mut self) -> Result<String, Error> {
         let agent_uuid = agent_uuid::get_uuid();
-        let base_url = format!(
-            "{}://{}:{}",
-            self.config.protocol, self.config.host, self.config.port
-        );
+        // With a server name override the URL uses it, the HTTP client resolves it to the host
+        let host = self
+            .config
+            .tls
+            .server_name
+            .as_deref()
+            .unwrap_or(&self.config.host);
+        let base_url = format!("{}://{}:{}", self.config.protocol, host, self.config.port);
 
         let url = match agent_uuid {
             Ok(uuid) => {
@@ -1,3 +1,5 @@
-mod grpc;
+mod event_queue;
+pub mod grpc;
 pub mod hub;
 pub mod routes;
+mod tls;
@@ -1,6 +1,11 @@
 use crate::agent_data::AgentData;
 use crate::configuration::Configuration;
-use axum::extract::State;
+use crate::duplicates::{self, DuplicateIndex, DuplicatesPage, PageRequest};
+use crate::event_pipeline::{PipelineCounters, PipelineMetrics};
+use crate::hash_cache::{HashCache, HashCacheMetrics};
+use crate::http::grpc::GrpcStatus;
+use crate::scan_report::ScanReport;
+use axum::extract::{Query, State};
 use axum::Json;
 use serde_derive::Serialize;
 use std::sync::{Arc, Mutex};
@@ -8,22 +13,34 @@ use std::sync::{Arc, Mutex};
 #[derive(Clone)]
 pub struct AgentDataState {
     pub agent_data: Arc<Mutex<AgentData>>,
+    pub grpc_status: Arc<Mutex<GrpcStatus>>,
 }
 
 #[derive(Clone)]
-pub struct GlobalConfigState {
-    pub config: Configuration,
+pub struct MetricsState {
+    pub pipeline_counters: Arc<PipelineCounters>,
+    pub hash_cache: Arc<HashCache>,
 }
 
-#[derive(Serialize)]
-pub struct Greeting {
-    message: String,
+#[derive(Clone)]
+pub struct DuplicatesState {
+    pub duplicates: Arc<Mutex<DuplicateIndex>>,
+}
+
+#[derive(Clone)]
+pub struct ScanReportState {
+    pub scan_report: Arc<Mutex<Option<ScanReport>>>,
+}
+
+#[derive(Clone)]
+pub struct GlobalConfigState {
+    pub config: Configuration,
 }
 
 pub async fn get_status(State(agent_data): State<AgentDataState>) -> Json<AgentData> {
     let mut agent_data_cloned = agent_data.agent_data.lock().unwrap().clone();
 
-    agent_data_cloned.update();
+    agent_data_cloned.update(agent_data.grpc_status.lock().unwrap().clone());
     Json(agent_data_cloned)
 }
 
@@ -40,3 +57,31 @@ pub async fn get_config(
 
     Json(response)
 }
+
+#[derive(Serialize)]
+pub struct GetMetricsResponseType {
+    event_pipeline: PipelineMetrics,
+    hash_cache: HashCacheMetrics,
+}
+
+pub async fn get_metrics(State(metrics): State<MetricsState>) -> Json<GetMetricsResponseType> {
+    Json(GetMetricsResponseType {
+        event_pipeline: metrics.pipeline_counters.snapshot(),
+        hash_cache: metrics.hash_cache.snapshot(),
+    })
+}
+
+pub async fn get_duplicates(
+    State(duplicates): State<DuplicatesState>,
+    Query(page_request): Query<PageRequest>,
+) -> Json<DuplicatesPage> {
+    let groups = duplicates.duplicates.lock().unwrap().snapshot();
+    Json(duplicates::page(groups, page_request))
+}
+
+/// Report of the last scan, null until a scan completed
+pub async fn get_scan_report(
+    State(scan_report): State<ScanReportState>,
+) -> Json<Option<ScanReport>> {
+    Json(scan_report.scan_report.lock().unwrap().clone())
+}
@@ -0,0 +1,124 @@
+use crate::configuration::TlsConfig;
+use crate::error::AgentError;
+use reqwest::{Certificate as HttpCertificate, ClientBuilder, Identity as HttpIdentity};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, AgentError> {
+    fs::read(path).map_err(|e| {
+        AgentError::InvalidTlsConfig(format!("could not read {}: {e}", path.display()))
+    })
+}
+
+// PEM encoded client certificate and private key
+type PemIdentity = (Vec<u8>, Vec<u8>);
+
+/// Loads the client certificate and key, both must be set for mutual TLS.
+fn read_identity(tls: &TlsConfig) -> Result<Option<PemIdentity>, AgentError> {
+    match (&tls.client_certificate, &tls.client_key) {
+        (Some(certificate), Some(key)) => Ok(Some((read_pem(certificate)?, read_pem(key)?))),
+        (None, None) => Ok(None),
+        _ => Err(AgentError::InvalidTlsConfig(String::from(
+            "client_certificate and client_key must be set together",
+        ))),
+    }
+}
+
+/// Applies the TLS configuration to the gRPC endpoint when the protocol is `https`.
+/// The configured CA bundle is the only trust anchor, so it is mandatory.
+pub fn configure_grpc_endpoint(
+    endpoint: Endpoint,
+    protocol: &str,
+    tls: &TlsConfig,
+) -> Result<Endpoint, AgentError> {
+    if protocol != "https" {
+        return Ok(endpoint);
+    }
+    let ca_certificate = match &tls.ca_certificate {
+        Some(ca_certificate) => read_pem(ca_certificate)?,
+        None => {
+            return Err(AgentError::InvalidTlsConfig(String::from(
+                "ca_certificate is required to connect to the gRPC server over https",
+            )))
+        }
+    };
+
+    let mut tls_config =
+        ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca_certificate));
+    if let Some((certificate, key)) = read_identity(tls)? {
+        tls_config = tls_config.identity(Identity::from_pem(certificate, key));
+    }
+    if let Some(server_name) = &tls.server_name {
+        tls_config = tls_config.domain_name(server_name);
+    }
+    endpoint
+        .tls_config(tls_config)
+        .map_err(|e| AgentError::InvalidTlsConfig(e.to_string()))
+}
+
+/// Applies the TLS configuration to the Hub HTTP client. When a CA bundle is set it replaces the
+/// system roots. A server name override is implemented by resolving it to the configured host, so
+/// the certificate is verified against the server name while connecting to the real address.
+pub fn configure_http_client(
+    mut builder: ClientBuilder,
+    host: &str,
+    port: &str,
+    tls: &TlsConfig,
+) -> Result<ClientBuilder, AgentError> {
+    if let Some(ca_certificate) = &tls.ca_certificate {
+        for certificate in HttpCertificate::from_pem_bundle(&read_pem(ca_certificate)?)
+            .map_err(|e| AgentError::InvalidTlsConfig(e.to_string()))?
+        {
+            builder = builder.add_root_certificate(certificate);
+        }
+        builder = builder.tls_built_in_root_certs(false);
+    }
+    if let Some((certificate, key)) = read_identity(tls)? {
+        let identity = HttpIdentity::from_pkcs8_pem(&certificate, &key)
+            .map_err(|e| AgentError::InvalidTlsConfig(e.to_string()))?;
+        builder = builder.identity(identity);
+    }
+    if let Some(server_name) = &tls.server_name {
+        let address = format!("{host}:{port}")
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| AgentError::InvalidTlsConfig(format!("could not resolve {host}")))?;
+        builder = builder.resolve(server_name, address);
+    }
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn https_grpc_requires_a_ca() {
+        let endpoint = Endpoint::from_static("https://localhost:5057");
+        assert!(matches!(
+            configure_grpc_endpoint(endpoint, "https", &TlsConfig::default()),
+            Err(AgentError::InvalidTlsConfig(_))
+        ));
+    }
+
+    #[test]
+    fn plain_http_ignores_tls_config() {
+        let endpoint = Endpoint::from_static("http://localhost:5057");
+        assert!(configure_grpc_endpoint(endpoint, "http", &TlsConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn client_certificate_without_key() {
+        let tls = TlsConfig {
+            client_certificate: Some(PathBuf::from("client.pem")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            read_identity(&tls),
+            Err(AgentError::InvalidTlsConfig(_))
+        ));
+    }
+}
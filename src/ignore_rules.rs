@@ -0,0 +1,230 @@
+use crate::configuration::FileSystemInterfaceConfig;
+use crate::file_info::fix_canonicalize_path;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+/// Name of the files holding the ignore rules of their directory and its subdirectories
+pub const IGNORE_FILE_NAME: &str = ".tidyignore";
+
+/// Files and directories left out of the scans and of the watcher events, with the gitignore
+/// syntax. The rules of the configuration apply to every watched directory, then come the
+/// rules of the directory itself, then the ones of the `.tidyignore` files from the watched
+/// directory down to the file: a rule of a deeper file wins, and the content of an ignored
+/// directory is ignored whatever its own rules.
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    patterns: Vec<String>,
+    directory_patterns: HashMap<PathBuf, Vec<String>>,
+    // Rules of the configuration, by canonical watched directory
+    roots: RwLock<HashMap<PathBuf, Gitignore>>,
+    // Rules of the ignore files, by the directory they are in
+    files: RwLock<BTreeMap<PathBuf, Gitignore>>,
+}
+
+fn build(root: &Path, patterns: &[String], source: Option<&Path>) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        if let Err(err) = builder.add_line(source.map(Path::to_path_buf), pattern) {
+            warn!("Skipping the ignore rule {pattern:?}: {err}");
+        }
+    }
+    builder.build().unwrap_or_else(|err| {
+        warn!(
+            "Could not build the ignore rules of {}: {err}",
+            root.display()
+        );
+        Gitignore::empty()
+    })
+}
+
+impl IgnoreRules {
+    pub fn new(config: &FileSystemInterfaceConfig) -> Self {
+        let rules = Self {
+            patterns: config.ignore_patterns.clone(),
+            directory_patterns: config
+                .directory_ignore_patterns
+                .iter()
+                .filter_map(|directory| {
+                    let root = fix_canonicalize_path(directory.dir.canonicalize().ok()?);
+                    Some((root, directory.patterns.clone()))
+                })
+                .collect(),
+            ..Default::default()
+        };
+        for directory in &config.dir {
+            rules.add_root(directory);
+        }
+        rules
+    }
+
+    /// Applies the rules of the configuration to a new watched directory.
+    pub fn add_root(&self, directory: &Path) {
+        let root = match directory.canonicalize() {
+            Ok(root) => fix_canonicalize_path(root),
+            Err(_) => return,
+        };
+        let mut patterns = self.patterns.clone();
+        if let Some(directory_patterns) = self.directory_patterns.get(&root) {
+            patterns.extend_from_slice(directory_patterns);
+        }
+        let rules = build(&root, &patterns, None);
+        self.roots.write().unwrap().insert(root, rules);
+    }
+
+    /// Reads the ignore file of `directory`, or forgets its rules when it has none anymore.
+    pub fn load_directory(&self, directory: &Path) {
+        let path = directory.join(IGNORE_FILE_NAME);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => {
+                self.files.write().unwrap().remove(directory);
+                return;
+            }
+        };
+        let patterns: Vec<String> = content.lines().map(String::from).collect();
+        let rules = build(directory, &patterns, Some(&path));
+        info!(
+            "Loaded {} ignore rules from {}",
+            rules.num_ignores() + rules.num_whitelists(),
+            path.display()
+        );
+        self.files
+            .write()
+            .unwrap()
+            .insert(directory.to_path_buf(), rules);
+    }
+
+    /// Reloads the rules when `path` is an ignore file, returns whether it is one.
+    pub fn reload_if_ignore_file(&self, path: &Path) -> bool {
+        match (path.file_name(), path.parent()) {
+            (Some(name), Some(directory)) if name == IGNORE_FILE_NAME => {
+                self.load_directory(directory);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `path`, or a directory between its watched directory and it, is ignored. Paths
+    /// outside of every watched directory are never ignored.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let roots = self.roots.read().unwrap();
+        let root = match root_of(&roots, path) {
+            Some(root) => root,
+            None => return false,
+        };
+        let mut directories: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|directory| *directory != root && directory.starts_with(root))
+            .collect();
+        directories.reverse();
+        directories
+            .into_iter()
+            .any(|directory| self.decide(&roots, root, directory, true))
+            || self.decide(&roots, root, path, is_dir)
+    }
+
+    /// Whether `path` itself is ignored, for a walk that does not enter ignored directories.
+    pub fn is_ignored_entry(&self, path: &Path, is_dir: bool) -> bool {
+        let roots = self.roots.read().unwrap();
+        match root_of(&roots, path) {
+            Some(root) => self.decide(&roots, root, path, is_dir),
+            None => false,
+        }
+    }
+
+    /// Applies the rules of the configuration, then the ones of the ignore files from the
+    /// watched directory down, the last matching rule wins.
+    fn decide(
+        &self,
+        roots: &HashMap<PathBuf, Gitignore>,
+        root: &Path,
+        path: &Path,
+        is_dir: bool,
+    ) -> bool {
+        let files = self.files.read().unwrap();
+        let mut directories: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|directory| directory.starts_with(root))
+            .collect();
+        directories.reverse();
+        let file_rules = directories
+            .into_iter()
+            .filter_map(|directory| files.get(directory));
+
+        let mut ignored = false;
+        for rules in roots.get(root).into_iter().chain(file_rules) {
+            match rules.matched(path, is_dir) {
+                Match::Ignore(_) => ignored = true,
+                Match::Whitelist(_) => ignored = false,
+                Match::None => (),
+            }
+        }
+        ignored
+    }
+}
+
+/// Innermost watched directory containing `path`
+fn root_of<'a>(roots: &'a HashMap<PathBuf, Gitignore>, path: &Path) -> Option<&'a Path> {
+    roots
+        .keys()
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+        .map(PathBuf::as_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::DirectoryIgnorePatterns;
+    use std::fs;
+
+    #[test]
+    fn configuration_and_ignore_files_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("project/logs")).unwrap();
+        fs::write(root.join(IGNORE_FILE_NAME), "*.log\n").unwrap();
+        fs::write(
+            root.join("project").join(IGNORE_FILE_NAME),
+            "!keep.log\nlogs/\n",
+        )
+        .unwrap();
+
+        let config = FileSystemInterfaceConfig {
+            dir: vec![root.clone()],
+            ignore_patterns: vec![String::from("node_modules/")],
+            directory_ignore_patterns: vec![DirectoryIgnorePatterns {
+                dir: root.clone(),
+                patterns: vec![String::from("/secret.txt")],
+            }],
+            ..crate::configuration::Configuration::default().filesystem_interface_config
+        };
+        let rules = IgnoreRules::new(&config);
+        rules.load_directory(&root);
+        rules.load_directory(&root.join("project"));
+
+        let ignored = |path: &str| rules.is_ignored(&root.join(path), false);
+        assert!(ignored("node_modules/package/index.js"));
+        assert!(ignored("secret.txt"));
+        assert!(!ignored("project/secret.txt"));
+        assert!(ignored("debug.log"));
+        assert!(!ignored("project/keep.log"));
+        assert!(ignored("project/other.log"));
+        assert!(ignored("project/logs/keep.log"));
+        assert!(!ignored("project/main.rs"));
+        assert!(!rules.is_ignored(Path::new("/elsewhere/debug.log"), false));
+
+        // The rules follow the changes of the ignore files
+        fs::remove_file(root.join(IGNORE_FILE_NAME)).unwrap();
+        assert!(rules.reload_if_ignore_file(&root.join(IGNORE_FILE_NAME)));
+        assert!(!ignored("debug.log"));
+        assert!(!rules.reload_if_ignore_file(&root.join("debug.log")));
+    }
+}
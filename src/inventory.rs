@@ -0,0 +1,482 @@
+use crate::duplicates::DuplicateIndex;
+use crate::error::AgentError;
+use crate::file_info::FileInfo;
+use crate::http::grpc::tidybee_events::{FileEventRequest, FileEventType, FolderEventRequest};
+use crate::posix_metadata::FileId;
+use crate::scan_report::SkippedPaths;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::warn;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InventoryEntry {
+    pub size: u64,
+    pub last_modified: SystemTime,
+    pub hash: Option<String>,
+    /// Path inside the archive of an archive entry, the key then being the archive path joined
+    /// with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inner_path: Option<PathBuf>,
+    /// Identity of the file, shared by its hard links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<FileId>,
+}
+
+/// Change of a file found on disk compared to the inventory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+/// Comparison of the files found in the watched directories with the inventory, fed file by
+/// file as the scan finds them. The scan finds the files in path order, so the sorted
+/// inventory is walked along with it: the known files it passes without finding them are the
+/// deleted ones, and only those are kept.
+#[derive(Debug, Default)]
+pub struct Reconciliation {
+    roots: Vec<PathBuf>,
+    // Last path found, the known files before it were passed
+    passed: Option<PathBuf>,
+    unseen: BTreeSet<PathBuf>,
+    pub created: usize,
+    pub updated: usize,
+}
+
+impl Reconciliation {
+    pub fn new(roots: &[PathBuf]) -> Self {
+        Self {
+            roots: roots.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Keeps the known files under the roots from the last path found up to `path`, or to the
+    /// end of the inventory.
+    fn pass(&mut self, inventory: &Inventory, path: Option<&Path>) {
+        let start = match &self.passed {
+            Some(passed) if path.is_some_and(|path| passed.as_path() >= path) => return,
+            Some(passed) => Bound::Excluded(passed.clone()),
+            None => Bound::Unbounded,
+        };
+        let end = path.map_or(Bound::Unbounded, |path| Bound::Excluded(path.to_path_buf()));
+        let roots = &self.roots;
+        self.unseen.extend(
+            inventory
+                .entries
+                .range((start, end))
+                .map(|(known, _)| known)
+                .filter(|known| roots.iter().any(|root| known.starts_with(root)))
+                .cloned(),
+        );
+        self.passed = path.map(Path::to_path_buf);
+    }
+
+    /// A file is updated when its size or hash changed, a new modification time alone does not
+    /// make it differ. Each file must be compared before its event is recorded.
+    pub fn compare(&mut self, inventory: &Inventory, info: &FileInfo) -> FileChange {
+        let path = info.full_path();
+        self.pass(inventory, Some(&path));
+        match inventory.entries.get(&path) {
+            Some(entry) => {
+                // Found out of order, when a watched directory is under another one
+                self.unseen.remove(&path);
+                if entry.size != info.size || entry.hash != info.hash {
+                    self.updated += 1;
+                    FileChange::Updated
+                } else {
+                    FileChange::Unchanged
+                }
+            }
+            None => {
+                self.created += 1;
+                FileChange::Created
+            }
+        }
+    }
+
+    /// Paths of the deleted files, with the path inside the archive for archive entries. The
+    /// files under the paths the scan could not read are not known to be deleted.
+    pub fn deleted(
+        mut self,
+        inventory: &Inventory,
+        skipped: &SkippedPaths,
+    ) -> Vec<(PathBuf, Option<PathBuf>)> {
+        self.pass(inventory, None);
+        self.unseen
+            .into_iter()
+            .filter(|path| !skipped.contains(path))
+            .filter_map(|path| match &inventory.entries.get(&path)?.inner_path {
+                Some(inner_path) => {
+                    let archive = path
+                        .ancestors()
+                        .nth(inner_path.components().count())
+                        .map(Path::to_path_buf);
+                    Some((archive.unwrap_or(path), Some(inner_path.clone())))
+                }
+                None => Some((path, None)),
+            })
+            .collect()
+    }
+}
+
+/// Last state of the watched files known to the Hub, persisted in the state directory so that
+/// only what changed while the agent was not running is sent on startup.
+///
+/// The inventory follows the events handed to the outbound queue: once queued they are
+/// guaranteed to reach the Hub, so they are considered synced.
+pub struct Inventory {
+    path: PathBuf,
+    entries: BTreeMap<PathBuf, InventoryEntry>,
+    dirty: bool,
+    duplicates: Arc<Mutex<DuplicateIndex>>,
+}
+
+impl Inventory {
+    /// Loads the inventory, a missing or unreadable file gives an empty inventory.
+    pub fn load(path: &Path) -> Self {
+        let entries: BTreeMap<PathBuf, InventoryEntry> = match fs::read(path) {
+            Ok(content) => match serde_json::from_slice(&content) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn!(
+                        "Discarding the unreadable inventory {}: {err}",
+                        path.display()
+                    );
+                    BTreeMap::new()
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        let mut duplicates = DuplicateIndex::default();
+        for (path, entry) in &entries {
+            duplicates.insert(path, entry.size, entry.hash.as_deref(), entry.file_id);
+        }
+        Self {
+            path: path.to_path_buf(),
+            entries,
+            dirty: false,
+            duplicates: Arc::new(Mutex::new(duplicates)),
+        }
+    }
+
+    /// Duplicate files among the inventory, shared with the HTTP server.
+    #[inline]
+    pub fn duplicates(&self) -> Arc<Mutex<DuplicateIndex>> {
+        self.duplicates.clone()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.dirty |= !self.entries.is_empty();
+        self.entries.clear();
+        self.duplicates.lock().unwrap().clear();
+    }
+
+    /// Paths inside the archive of the known entries of an archive
+    pub fn entries_of(&self, archive: &Path) -> Vec<PathBuf> {
+        self.entries
+            .range(archive.to_path_buf()..)
+            .skip_while(|(path, _)| path.as_path() == archive)
+            .take_while(|(path, _)| path.starts_with(archive))
+            .filter_map(|(path, entry)| {
+                let inner_path = entry.inner_path.as_ref()?;
+                (archive.join(inner_path) == *path).then(|| inner_path.clone())
+            })
+            .collect()
+    }
+
+    pub fn record_file_event(&mut self, event: &FileEventRequest) {
+        if event.path.is_empty() {
+            return;
+        }
+        // Archive entries are keyed by the archive path joined with their path inside it
+        let path: PathBuf = event.path.iter().collect();
+        let inner_path = event.path.get(1).map(PathBuf::from);
+        match event.event_type() {
+            FileEventType::Created | FileEventType::Updated => {
+                let last_modified = event
+                    .last_modified
+                    .clone()
+                    .and_then(|timestamp| SystemTime::try_from(timestamp).ok())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                self.insert(
+                    path,
+                    InventoryEntry {
+                        size: event.size.unwrap_or_default(),
+                        last_modified,
+                        hash: event.hash.clone(),
+                        file_id: event.posix.as_ref().map(|posix| FileId {
+                            device: posix.device,
+                            inode: posix.inode,
+                        }),
+                        inner_path,
+                    },
+                );
+            }
+            FileEventType::Deleted => {
+                self.remove(&path);
+            }
+            _ => return,
+        }
+        self.dirty = true;
+    }
+
+    pub fn record_folder_event(&mut self, event: &FolderEventRequest) {
+        let old_path = PathBuf::from(&event.old_path);
+        // The entries under the folder follow it in the sorted inventory
+        let children: Vec<PathBuf> = self
+            .entries
+            .range(old_path.clone()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(&old_path))
+            .cloned()
+            .collect();
+        let new_path = match (event.event_type(), &event.new_path) {
+            (FileEventType::Deleted, _) => None,
+            (FileEventType::Moved, Some(new_path)) => Some(PathBuf::from(new_path)),
+            _ => return,
+        };
+        for child in children {
+            if let Some(entry) = self.remove(&child) {
+                if let (Some(new_path), Ok(relative)) = (&new_path, child.strip_prefix(&old_path)) {
+                    self.insert(new_path.join(relative), entry);
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    fn insert(&mut self, path: PathBuf, entry: InventoryEntry) {
+        self.duplicates.lock().unwrap().insert(
+            &path,
+            entry.size,
+            entry.hash.as_deref(),
+            entry.file_id,
+        );
+        self.entries.insert(path, entry);
+    }
+
+    fn remove(&mut self, path: &Path) -> Option<InventoryEntry> {
+        self.duplicates.lock().unwrap().remove(path);
+        self.entries.remove(path)
+    }
+
+    /// Writes the inventory to disk if it changed since the last save.
+    pub fn save(&mut self) -> Result<(), AgentError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(
+            &temporary_path,
+            serde_json::to_vec(&self.entries).map_err(std::io::Error::from)?,
+        )?;
+        fs::rename(&temporary_path, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_info(path: &str, size: u64, hash: &str) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from(path),
+            size,
+            hash: Some(String::from(hash)),
+            ..Default::default()
+        }
+    }
+
+    fn created_event(path: &str, size: u64, hash: &str) -> FileEventRequest {
+        FileEventRequest {
+            event_type: FileEventType::Created as i32,
+            path: vec![String::from(path)],
+            size: Some(size),
+            hash: Some(String::from(hash)),
+            ..Default::default()
+        }
+    }
+
+    type Delta = (
+        Vec<FileInfo>,
+        Vec<FileInfo>,
+        Vec<(PathBuf, Option<PathBuf>)>,
+    );
+
+    /// Created, updated and deleted files of a scan of `/w` finding `files`
+    fn reconcile(inventory: &Inventory, files: Vec<FileInfo>) -> Delta {
+        reconcile_skipping(inventory, files, &SkippedPaths::default())
+    }
+
+    fn reconcile_skipping(
+        inventory: &Inventory,
+        files: Vec<FileInfo>,
+        skipped: &SkippedPaths,
+    ) -> Delta {
+        let mut reconciliation = Reconciliation::new(&[PathBuf::from("/w")]);
+        let (mut created, mut updated) = (Vec::new(), Vec::new());
+        for info in files {
+            match reconciliation.compare(inventory, &info) {
+                FileChange::Created => created.push(info),
+                FileChange::Updated => updated.push(info),
+                FileChange::Unchanged => (),
+            }
+        }
+        (created, updated, reconciliation.deleted(inventory, skipped))
+    }
+
+    #[test]
+    fn reconciles_against_the_saved_inventory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inventory.json");
+        let mut inventory = Inventory::load(&path);
+        inventory.record_file_event(&created_event("/w/kept", 1, "a"));
+        inventory.record_file_event(&created_event("/w/changed", 1, "b"));
+        inventory.record_file_event(&created_event("/w/removed", 1, "c"));
+        inventory.record_file_event(&created_event("/unwatched/file", 1, "f"));
+        inventory.save().unwrap();
+
+        let inventory = Inventory::load(&path);
+        assert_eq!(inventory.len(), 4);
+        let (created, updated, deleted) = reconcile(
+            &inventory,
+            vec![
+                file_info("/w/kept", 1, "a"),
+                file_info("/w/changed", 1, "d"),
+                file_info("/w/new", 2, "e"),
+            ],
+        );
+        assert_eq!(created, vec![file_info("/w/new", 2, "e")]);
+        assert_eq!(updated[0].path, PathBuf::from("/w/changed"));
+        assert_eq!(deleted, vec![(PathBuf::from("/w/removed"), None)]);
+    }
+
+    #[test]
+    fn files_found_out_of_order_are_not_deleted() {
+        let mut inventory = Inventory::load(Path::new("does-not-exist.json"));
+        for path in ["/w/a", "/w/b", "/w/c", "/w/sub/d"] {
+            inventory.record_file_event(&created_event(path, 1, "a"));
+        }
+        // A watched directory under another one is scanned twice
+        let (created, updated, deleted) = reconcile(
+            &inventory,
+            vec![
+                file_info("/w/b", 1, "a"),
+                file_info("/w/sub/d", 1, "a"),
+                file_info("/w/a", 1, "a"),
+                file_info("/w/sub/d", 1, "a"),
+            ],
+        );
+        assert!(created.is_empty() && updated.is_empty());
+        assert_eq!(deleted, vec![(PathBuf::from("/w/c"), None)]);
+    }
+
+    #[test]
+    fn files_under_skipped_paths_are_not_deleted() {
+        let mut inventory = Inventory::load(Path::new("does-not-exist.json"));
+        for path in [
+            "/w/locked/a",
+            "/w/locked/sub/b",
+            "/w/unreadable",
+            "/w/removed",
+        ] {
+            inventory.record_file_event(&created_event(path, 1, "a"));
+        }
+        let mut report = crate::scan_report::ScanReport::new(&[PathBuf::from("/w")]);
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        report.record_skipped_directory(Path::new("/w/locked"), &denied);
+        report.record_io_error(Path::new("/w/unreadable"), &denied);
+
+        let (_, _, deleted) = reconcile_skipping(&inventory, Vec::new(), &report.skipped_paths);
+        assert_eq!(deleted, vec![(PathBuf::from("/w/removed"), None)]);
+    }
+
+    #[test]
+    fn archive_entries_are_children_of_their_archive() {
+        let mut inventory = Inventory::load(Path::new("does-not-exist.json"));
+        inventory.record_file_event(&created_event("/w/files.zip", 10, "z"));
+        inventory.record_file_event(&created_event("/w/files.zip.bak", 10, "z"));
+        for inner_path in ["a.txt", "sub/b.txt"] {
+            let mut event = created_event("/w/files.zip", 1, inner_path);
+            event.path.push(String::from(inner_path));
+            inventory.record_file_event(&event);
+        }
+        assert_eq!(
+            inventory.entries_of(Path::new("/w/files.zip")),
+            vec![PathBuf::from("a.txt"), PathBuf::from("sub/b.txt")]
+        );
+        assert!(inventory
+            .entries_of(Path::new("/w/files.zip.bak"))
+            .is_empty());
+
+        let (created, updated, deleted) = reconcile(
+            &inventory,
+            vec![
+                file_info("/w/files.zip", 10, "z"),
+                file_info("/w/files.zip.bak", 10, "z"),
+                FileInfo {
+                    inner_path: Some(PathBuf::from("a.txt")),
+                    ..file_info("/w/files.zip", 1, "a.txt")
+                },
+            ],
+        );
+        assert!(created.is_empty() && updated.is_empty());
+        assert_eq!(
+            deleted,
+            vec![(
+                PathBuf::from("/w/files.zip"),
+                Some(PathBuf::from("sub/b.txt"))
+            )]
+        );
+    }
+
+    #[test]
+    fn folder_events_apply_to_children() {
+        let mut inventory = Inventory::load(Path::new("does-not-exist.json"));
+        inventory.record_file_event(&created_event("/w/dir/a", 1, "a"));
+        inventory.record_file_event(&created_event("/w/dir/sub/b", 1, "b"));
+        inventory.record_file_event(&created_event("/w/other", 1, "c"));
+
+        inventory.record_folder_event(&FolderEventRequest {
+            event_type: FileEventType::Moved as i32,
+            old_path: String::from("/w/dir"),
+            new_path: Some(String::from("/w/moved")),
+            sequence: 0,
+            ..Default::default()
+        });
+        assert_eq!(
+            inventory.entries.keys().cloned().collect::<Vec<_>>(),
+            vec![
+                PathBuf::from("/w/moved/a"),
+                PathBuf::from("/w/moved/sub/b"),
+                PathBuf::from("/w/other"),
+            ]
+        );
+
+        inventory.record_folder_event(&FolderEventRequest {
+            event_type: FileEventType::Deleted as i32,
+            old_path: String::from("/w/moved"),
+            new_path: None,
+            sequence: 0,
+            ..Default::default()
+        });
+        assert_eq!(inventory.len(), 1);
+    }
+}
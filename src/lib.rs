@@ -1,22 +1,42 @@
-use crate::configuration::Configuration;
+use crate::configuration::{Configuration, StartupMode};
 use crate::error::AgentError;
+use crate::event_pipeline::PipelineCounters;
+use crate::file_lister::ScanOptions;
+use crate::hash_cache::HashCache;
 use crate::http::hub::Hub;
 use crate::server::ServerBuilder;
+use crate::stable_writes::StableWrites;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
+use std::sync::mpsc as sync_mpsc;
+use std::sync::Arc;
 use std::{borrow, env, thread};
-use tokio::{sync::mpsc, time};
-use tracing::{error, Level};
+use tokio::time;
+use tracing::{error, info, Level};
 
 mod agent_data;
 mod agent_uuid;
-mod configuration;
+mod archive;
+pub mod configuration;
+mod content_type;
+mod duplicates;
 mod error;
+mod event_pipeline;
 mod file_info;
 mod file_lister;
 mod file_watcher;
+mod hash_cache;
 mod http;
+mod ignore_rules;
+mod inventory;
+mod media_metadata;
+#[cfg(feature = "mock-hub")]
+pub mod mock_hub;
+mod posix_metadata;
+mod scan_report;
 mod server;
+mod stable_writes;
+mod watched_root;
 
 lazy_static! {
     static ref CLI_LOGGING_LEVEL: HashMap<String, Level> = {
@@ -61,8 +81,31 @@ pub async fn run() -> Result<(), AgentError> {
         }
     };
 
+    run_with_configuration(config).await
+}
+
+/// Runs the agent with an already loaded configuration, logging must be set up by the caller.
+pub async fn run_with_configuration(config: Configuration) -> Result<(), AgentError> {
+    let hash_cache = Arc::new(HashCache::load(
+        &config.state_config.dir.join("hash_cache.json"),
+        config.filesystem_interface_config.hash_algorithm,
+    ));
+    let mut hub_client = Hub::new(
+        config.hub_config.clone(),
+        &config.state_config,
+        hash_cache.clone(),
+    )
+    .unwrap();
+
+    let pipeline_counters = Arc::new(PipelineCounters::default());
+
     let server = ServerBuilder::new()
         .inject_global_configuration(config.clone())
+        .inject_grpc_status(hub_client.grpc_client.status())
+        .inject_pipeline_counters(pipeline_counters.clone())
+        .inject_hash_cache(hash_cache.clone())
+        .inject_duplicates(hub_client.grpc_client.duplicates())
+        .inject_scan_report(hub_client.grpc_client.scan_report())
         .build(
             config.agent_data.latest_version.clone(),
             config.agent_data.minimal_version.clone(),
@@ -71,8 +114,6 @@ pub async fn run() -> Result<(), AgentError> {
             &config.server_config.log_level,
         );
 
-    let mut hub_client = Hub::new(config.hub_config.clone()).unwrap();
-
     tokio::spawn(async move {
         server.start().await;
     });
@@ -92,37 +133,76 @@ pub async fn run() -> Result<(), AgentError> {
         timeout *= 2;
     }
 
-    match file_lister::list_directories(config.clone().filesystem_interface_config.dir) {
-        Ok(files_vec) => {
-            if let Err(err) = hub_client
-                .grpc_client
-                .send_create_events_once(files_vec)
-                .await
-            {
-                error!("{err}");
-            }
-        }
-        Err(error) => {
-            error!("{}", error);
-        }
-    }
-
-    let (file_watcher_sender, file_watcher_receiver) = mpsc::unbounded_channel();
+    let (file_watcher_sender, file_watcher_receiver) = event_pipeline::channel(
+        &config.event_pipeline_config,
+        &config.state_config.dir.join("event_spill.jsonl"),
+        pipeline_counters.clone(),
+    );
+    let stable_writes = StableWrites::new(
+        time::Duration::from_millis(
+            config
+                .filesystem_interface_config
+                .stable_write_quiet_period_ms,
+        ),
+        pipeline_counters,
+    );
+    let (watch_request_sender, watch_request_receiver) = sync_mpsc::channel();
+    hub_client.grpc_client.attach_watcher(
+        config.filesystem_interface_config.dir.clone(),
+        &config.filesystem_interface_config.allowed_watch_roots,
+        watch_request_sender,
+    );
+    let watched_directories = config.filesystem_interface_config.dir.clone();
+    // The watcher and the scans share the ignore rules
+    let scan_options = ScanOptions::new(&config.filesystem_interface_config);
+    let ignore_rules = scan_options.ignore_rules.clone();
     let file_watcher_thread: thread::JoinHandle<()> = thread::spawn(move || {
         file_watcher::watch_directories(
-            config.filesystem_interface_config.dir.clone(),
+            watched_directories,
             file_watcher_sender,
+            watch_request_receiver,
+            stable_writes,
+            ignore_rules,
         );
     });
 
-    if let Err(err) = hub_client
+    // The scan runs on the blocking pool so that the HTTP server stays responsive, and its files
+    // are sent as they are found. The watcher already queues the changes happening meanwhile;
+    // they are sent once the scan was sent
+    file_lister::configure_scan_threads(config.filesystem_interface_config.scan_threads);
+    hub_client
         .grpc_client
-        .send_events(file_watcher_receiver)
-        .await
-    {
+        .set_scan_options(scan_options.clone());
+    let scan = file_lister::stream_directories(
+        config.filesystem_interface_config.dir.clone(),
+        hash_cache.clone(),
+        scan_options,
+    );
+    let result = match config.startup_config.mode {
+        StartupMode::Full => hub_client.grpc_client.send_create_events_once(scan).await,
+        StartupMode::Reconcile => {
+            hub_client
+                .grpc_client
+                .send_reconciled_events(&config.filesystem_interface_config.dir, scan)
+                .await
+        }
+    };
+    if let Err(err) = result {
         error!("{err}");
     }
 
-    file_watcher_thread.join().unwrap();
+    tokio::select! {
+        result = hub_client.grpc_client.send_events(file_watcher_receiver) => {
+            if let Err(err) = result {
+                error!("{err}");
+            }
+            file_watcher_thread.join().unwrap();
+        }
+        _ = tokio::signal::ctrl_c() => {
+            // The events not sent yet are in the outbound queue, the watcher stops with the process
+            info!("Shutting down");
+            hub_client.grpc_client.save_inventory();
+        }
+    }
     Ok(())
 }
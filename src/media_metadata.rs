@@ -0,0 +1,312 @@
+use crate::content_type::FileCategory;
+use exif::{In, Tag as ExifTag, Value};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+use tracing::debug;
+
+/// What the EXIF data of a photo tells about it
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct ImageMetadata {
+    /// ISO 8601 date and time the photo was taken, with its offset when the camera recorded it
+    pub captured_at: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub has_gps: bool,
+    /// EXIF orientation, 1 to 8
+    pub orientation: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Properties of the main track of an audio or video file and its tags
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct AudioVideoMetadata {
+    pub duration_ms: Option<u64>,
+    pub codec: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaMetadata {
+    Image(ImageMetadata),
+    AudioVideo(AudioVideoMetadata),
+}
+
+/// Extracts the metadata of photos and media files, None for other categories or when the file
+/// carries no readable metadata.
+pub fn extract(path: &Path, category: FileCategory) -> Option<MediaMetadata> {
+    match category {
+        FileCategory::Image => image_metadata(path).map(MediaMetadata::Image),
+        FileCategory::Audio | FileCategory::Video => {
+            audio_video_metadata(path).map(MediaMetadata::AudioVideo)
+        }
+        _ => None,
+    }
+}
+
+// region: --- EXIF
+
+fn exif_string(exif: &exif::Exif, tag: ExifTag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(char::from(0)).trim();
+            (!value.is_empty()).then(|| value.to_owned())
+        }
+        _ => None,
+    }
+}
+
+fn exif_uint(exif: &exif::Exif, tag: ExifTag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+fn captured_at(exif: &exif::Exif) -> Option<String> {
+    let mut date_time = [ExifTag::DateTimeOriginal, ExifTag::DateTime]
+        .into_iter()
+        .find_map(|tag| match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok(),
+            _ => None,
+        })?;
+    if let Some(Value::Ascii(values)) = exif
+        .get_field(ExifTag::OffsetTimeOriginal, In::PRIMARY)
+        .map(|field| &field.value)
+    {
+        if let Some(offset) = values.first() {
+            // An unparsable offset leaves the local time
+            let _ = date_time.parse_offset(offset);
+        }
+    }
+
+    let local = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        date_time.year,
+        date_time.month,
+        date_time.day,
+        date_time.hour,
+        date_time.minute,
+        date_time.second
+    );
+    Some(match date_time.offset {
+        Some(offset) => format!(
+            "{local}{}{:02}:{:02}",
+            if offset < 0 { '-' } else { '+' },
+            offset.unsigned_abs() / 60,
+            offset.unsigned_abs() % 60
+        ),
+        None => local,
+    })
+}
+
+fn image_metadata(path: &Path) -> Option<ImageMetadata> {
+    let file = File::open(path).ok()?;
+    let exif = match exif::Reader::new().read_from_container(&mut BufReader::new(file)) {
+        Ok(exif) => exif,
+        Err(err) => {
+            debug!("No EXIF data in {}: {err}", path.display());
+            return None;
+        }
+    };
+    Some(ImageMetadata {
+        captured_at: captured_at(&exif),
+        camera_make: exif_string(&exif, ExifTag::Make),
+        camera_model: exif_string(&exif, ExifTag::Model),
+        has_gps: exif.get_field(ExifTag::GPSLatitude, In::PRIMARY).is_some(),
+        orientation: exif_uint(&exif, ExifTag::Orientation),
+        width: exif_uint(&exif, ExifTag::PixelXDimension)
+            .or_else(|| exif_uint(&exif, ExifTag::ImageWidth)),
+        height: exif_uint(&exif, ExifTag::PixelYDimension)
+            .or_else(|| exif_uint(&exif, ExifTag::ImageLength)),
+    })
+}
+
+// endregion: --- EXIF
+
+// region: --- Audio and video
+
+fn apply_tags(metadata: &mut AudioVideoMetadata, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+        let field = match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => &mut metadata.title,
+            Some(StandardTagKey::Artist) => &mut metadata.artist,
+            Some(StandardTagKey::Album) => &mut metadata.album,
+            _ => continue,
+        };
+        let value = tag.value.to_string();
+        let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        if field.is_none() && !value.is_empty() {
+            *field = Some(value.to_owned());
+        }
+    }
+}
+
+fn audio_video_metadata(path: &Path) -> Option<AudioVideoMetadata> {
+    let file = File::open(path).ok()?;
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut probed = match symphonia::default::get_probe().format(
+        &hint,
+        MediaSourceStream::new(Box::new(file), Default::default()),
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) {
+        Ok(probed) => probed,
+        Err(err) => {
+            debug!("Unsupported media container {}: {err}", path.display());
+            return None;
+        }
+    };
+
+    let mut metadata = AudioVideoMetadata::default();
+    if let Some(track) = probed.format.default_track() {
+        let params = &track.codec_params;
+        metadata.duration_ms = params
+            .time_base
+            .zip(params.n_frames)
+            .map(|(time_base, frames)| {
+                let time = time_base.calc_time(frames);
+                time.seconds * 1000 + (time.frac * 1000.0) as u64
+            });
+        metadata.codec = symphonia::default::get_codecs()
+            .get_codec(params.codec)
+            .map(|codec| String::from(codec.short_name));
+    }
+    // Tags in the container win over the ones found before it, such as ID3 tags
+    if let Some(revision) = probed.format.metadata().current() {
+        apply_tags(&mut metadata, revision);
+    }
+    if let Some(probed_metadata) = probed.metadata.get() {
+        if let Some(revision) = probed_metadata.current() {
+            apply_tags(&mut metadata, revision);
+        }
+    }
+    Some(metadata)
+}
+
+// endregion: --- Audio and video
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::Field;
+    use std::io::Cursor;
+
+    fn ascii_field(tag: ExifTag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    #[test]
+    fn reads_exif_from_jpeg() {
+        let fields = [
+            ascii_field(ExifTag::Make, "Bee"),
+            ascii_field(ExifTag::Model, "Hive 2"),
+            ascii_field(ExifTag::DateTimeOriginal, "2023:07:14 18:30:05"),
+            ascii_field(ExifTag::OffsetTimeOriginal, "+02:00"),
+            Field {
+                tag: ExifTag::Orientation,
+                ifd_num: In::PRIMARY,
+                value: Value::Short(vec![6]),
+            },
+            Field {
+                tag: ExifTag::PixelXDimension,
+                ifd_num: In::PRIMARY,
+                value: Value::Long(vec![4000]),
+            },
+            Field {
+                tag: ExifTag::PixelYDimension,
+                ifd_num: In::PRIMARY,
+                value: Value::Long(vec![3000]),
+            },
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+        jpeg.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(&[0xff, 0xd9]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, jpeg).unwrap();
+
+        assert_eq!(
+            extract(&path, FileCategory::Image),
+            Some(MediaMetadata::Image(ImageMetadata {
+                captured_at: Some(String::from("2023-07-14T18:30:05+02:00")),
+                camera_make: Some(String::from("Bee")),
+                camera_model: Some(String::from("Hive 2")),
+                has_gps: false,
+                orientation: Some(6),
+                width: Some(4000),
+                height: Some(3000),
+            }))
+        );
+        assert_eq!(extract(&path, FileCategory::Document), None);
+    }
+
+    #[test]
+    fn reads_duration_codec_and_tags_from_wav() {
+        let title = b"Buzz\0\0";
+        let mut info = b"INFO".to_vec();
+        info.extend_from_slice(b"INAM");
+        info.extend_from_slice(&(title.len() as u32).to_le_bytes());
+        info.extend_from_slice(title);
+        // Half a second of 8 kHz mono 16 bits silence
+        let samples = vec![0u8; 8000];
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(
+            &((4 + 24 + 8 + info.len() + 8 + samples.len()) as u32).to_le_bytes(),
+        );
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"LIST");
+        wav.extend_from_slice(&(info.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&info);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&samples);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("buzz.wav");
+        std::fs::write(&path, wav).unwrap();
+
+        assert_eq!(
+            extract(&path, FileCategory::Audio),
+            Some(MediaMetadata::AudioVideo(AudioVideoMetadata {
+                duration_ms: Some(500),
+                codec: Some(String::from("pcm_s16le")),
+                title: Some(String::from("Buzz")),
+                artist: None,
+                album: None,
+            }))
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! In-process stand-in for the TidyBee Hub, used to exercise the agent end to end.
+//!
+//! It serves the HTTP authentication endpoints and the `TidyBeeEvents` gRPC service, records every
+//! request it receives, and lets the caller push commands to the connected agents.
+
+use crate::configuration::HubConfig;
+use axum::{extract::Path, extract::State, routing::post, Json, Router};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::Stream;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::info;
+
+pub use crate::http::grpc::tidybee_events;
+use tidybee_events::tidy_bee_events_server::{TidyBeeEvents, TidyBeeEventsServer};
+use tidybee_events::{
+    CommandResult, FileEventRequest, FileInfoEventResponse, FolderEventRequest, HubCommand,
+    ScanReportRequest, ScanReportResponse, Status as EventStatus,
+};
+
+/// Identifier handed out to every agent authenticating against the mock Hub
+pub const MOCK_AGENT_ID: &str = "00000000-0000-4000-8000-000000000000";
+
+// How often `wait_for` checks the recorded requests
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const COMMAND_CHANNEL_CAPACITY: usize = 16;
+
+// Requests are recorded for the assertions of the tests, their size does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockHubRequest {
+    Auth {
+        agent_id: Option<String>,
+        body: String,
+    },
+    Disconnect {
+        agent_id: String,
+    },
+    FileEvent(FileEventRequest),
+    FolderEvent(FolderEventRequest),
+    CommandResult(CommandResult),
+    ScanReport(ScanReportRequest),
+}
+
+#[derive(Default)]
+struct MockHubState {
+    requests: Mutex<Vec<MockHubRequest>>,
+    command_senders: Mutex<Vec<mpsc::Sender<Result<HubCommand, Status>>>>,
+}
+
+impl MockHubState {
+    fn record(&self, request: MockHubRequest) {
+        info!("Mock Hub received {:?}", request);
+        self.requests.lock().unwrap().push(request);
+    }
+}
+
+// region: --- HTTP endpoints
+
+async fn auth(State(state): State<Arc<MockHubState>>, Json(body): Json<String>) -> Json<String> {
+    state.record(MockHubRequest::Auth {
+        agent_id: None,
+        body,
+    });
+    Json(String::from(MOCK_AGENT_ID))
+}
+
+async fn reauth(
+    State(state): State<Arc<MockHubState>>,
+    Path(agent_id): Path<String>,
+    Json(body): Json<String>,
+) -> Json<String> {
+    state.record(MockHubRequest::Auth {
+        agent_id: Some(agent_id),
+        body,
+    });
+    Json(String::from(MOCK_AGENT_ID))
+}
+
+async fn disconnect(State(state): State<Arc<MockHubState>>, Path(agent_id): Path<String>) {
+    state.record(MockHubRequest::Disconnect { agent_id });
+}
+
+fn router(hub_config: &HubConfig, state: Arc<MockHubState>) -> Router {
+    Router::new()
+        .route(&hub_config.auth_path, post(auth))
+        .route(&format!("{}/:agent_id", hub_config.auth_path), post(reauth))
+        .route(
+            &hub_config
+                .disconnect_path
+                .replace("{agent_id}", ":agent_id"),
+            post(disconnect),
+        )
+        .with_state(state)
+}
+
+// endregion: --- HTTP endpoints
+
+// region: --- gRPC service
+
+struct EventsService {
+    state: Arc<MockHubState>,
+}
+
+fn is_authorized<T>(request: &Request<T>) -> bool {
+    let expected = format!("Bearer {MOCK_AGENT_ID}");
+    request
+        .metadata()
+        .get("authorization")
+        .is_some_and(|value| value.to_str().is_ok_and(|value| value == expected))
+}
+
+fn unauthenticated() -> Status {
+    Status::unauthenticated("invalid or missing agent id")
+}
+
+fn acknowledge(acknowledged_sequence: u64) -> Response<FileInfoEventResponse> {
+    Response::new(FileInfoEventResponse {
+        status: EventStatus::Ok as i32,
+        acknowledged_sequence,
+    })
+}
+
+#[tonic::async_trait]
+impl TidyBeeEvents for EventsService {
+    async fn file_event(
+        &self,
+        request: Request<Streaming<FileEventRequest>>,
+    ) -> Result<Response<FileInfoEventResponse>, Status> {
+        if !is_authorized(&request) {
+            return Err(unauthenticated());
+        }
+        let mut events = request.into_inner();
+        let mut acknowledged_sequence = 0;
+        while let Some(event) = events.message().await? {
+            acknowledged_sequence = acknowledged_sequence.max(event.sequence);
+            self.state.record(MockHubRequest::FileEvent(event));
+        }
+        Ok(acknowledge(acknowledged_sequence))
+    }
+
+    async fn folder_event(
+        &self,
+        request: Request<Streaming<FolderEventRequest>>,
+    ) -> Result<Response<FileInfoEventResponse>, Status> {
+        if !is_authorized(&request) {
+            return Err(unauthenticated());
+        }
+        let mut events = request.into_inner();
+        let mut acknowledged_sequence = 0;
+        while let Some(event) = events.message().await? {
+            acknowledged_sequence = acknowledged_sequence.max(event.sequence);
+            self.state.record(MockHubRequest::FolderEvent(event));
+        }
+        Ok(acknowledge(acknowledged_sequence))
+    }
+
+    type CommandChannelStream = Pin<Box<dyn Stream<Item = Result<HubCommand, Status>> + Send>>;
+
+    async fn command_channel(
+        &self,
+        request: Request<Streaming<CommandResult>>,
+    ) -> Result<Response<Self::CommandChannelStream>, Status> {
+        if !is_authorized(&request) {
+            return Err(unauthenticated());
+        }
+        let mut results = request.into_inner();
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(result)) = results.message().await {
+                state.record(MockHubRequest::CommandResult(result));
+            }
+        });
+
+        let (sender, receiver) = mpsc::channel(COMMAND_CHANNEL_CAPACITY);
+        self.state.command_senders.lock().unwrap().push(sender);
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    async fn scan_report(
+        &self,
+        request: Request<ScanReportRequest>,
+    ) -> Result<Response<ScanReportResponse>, Status> {
+        if !is_authorized(&request) {
+            return Err(unauthenticated());
+        }
+        self.state
+            .record(MockHubRequest::ScanReport(request.into_inner()));
+        Ok(Response::new(ScanReportResponse {
+            status: EventStatus::Ok as i32,
+        }))
+    }
+}
+
+// endregion: --- gRPC service
+
+pub struct MockHub {
+    http_address: SocketAddr,
+    grpc_address: SocketAddr,
+    state: Arc<MockHubState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MockHub {
+    /// Binds both servers, use port 0 to let the system pick free ports. The endpoint paths are
+    /// taken from `hub_config` so they match what the agent calls.
+    pub async fn start(
+        http_address: SocketAddr,
+        grpc_address: SocketAddr,
+        hub_config: &HubConfig,
+    ) -> std::io::Result<Self> {
+        let state = Arc::new(MockHubState::default());
+
+        let http_listener = TcpListener::bind(http_address).await?;
+        let grpc_listener = TcpListener::bind(grpc_address).await?;
+        let http_address = http_listener.local_addr()?;
+        let grpc_address = grpc_listener.local_addr()?;
+
+        let router = router(hub_config, state.clone());
+        let http_task = tokio::spawn(async move {
+            if let Err(err) = axum::serve(http_listener, router).await {
+                tracing::error!("Mock Hub HTTP server stopped: {err}");
+            }
+        });
+        let service = TidyBeeEventsServer::new(EventsService {
+            state: state.clone(),
+        })
+        .accept_compressed(CompressionEncoding::Gzip)
+        .accept_compressed(CompressionEncoding::Zstd);
+        let grpc_task = tokio::spawn(async move {
+            if let Err(err) = Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(grpc_listener))
+                .await
+            {
+                tracing::error!("Mock Hub gRPC server stopped: {err}");
+            }
+        });
+
+        info!("Mock Hub listening on {http_address} (HTTP) and {grpc_address} (gRPC)");
+        Ok(Self {
+            http_address,
+            grpc_address,
+            state,
+            tasks: vec![http_task, grpc_task],
+        })
+    }
+
+    pub fn http_address(&self) -> SocketAddr {
+        self.http_address
+    }
+
+    pub fn grpc_address(&self) -> SocketAddr {
+        self.grpc_address
+    }
+
+    /// Points the Hub and gRPC server addresses of `hub_config` to this mock.
+    pub fn configure(&self, hub_config: &mut HubConfig) {
+        hub_config.host = self.http_address.ip().to_string();
+        hub_config.port = self.http_address.port().to_string();
+        hub_config.protocol = String::from("http");
+        hub_config.grpc_server.host = self.grpc_address.ip().to_string();
+        hub_config.grpc_server.port = self.grpc_address.port();
+        hub_config.grpc_server.protocol = String::from("http");
+    }
+
+    /// Every request received so far, in arrival order.
+    pub fn requests(&self) -> Vec<MockHubRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    pub fn file_events(&self) -> Vec<FileEventRequest> {
+        self.requests()
+            .into_iter()
+            .filter_map(|request| match request {
+                MockHubRequest::FileEvent(event) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn folder_events(&self) -> Vec<FolderEventRequest> {
+        self.requests()
+            .into_iter()
+            .filter_map(|request| match request {
+                MockHubRequest::FolderEvent(event) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn command_results(&self) -> Vec<CommandResult> {
+        self.requests()
+            .into_iter()
+            .filter_map(|request| match request {
+                MockHubRequest::CommandResult(result) => Some(result),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sends a command on every open command channel, returns how many agents received it.
+    pub fn send_command(&self, command: HubCommand) -> usize {
+        let mut senders = self.state.command_senders.lock().unwrap();
+        senders.retain(|sender| !sender.is_closed());
+        senders
+            .iter()
+            .filter(|sender| sender.try_send(Ok(command.clone())).is_ok())
+            .count()
+    }
+
+    /// Waits until `condition` holds for the recorded requests, returns false on timeout.
+    pub async fn wait_for<F>(&self, timeout: Duration, condition: F) -> bool
+    where
+        F: Fn(&[MockHubRequest]) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if condition(&self.state.requests.lock().unwrap()) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            time::sleep(WAIT_POLL_INTERVAL).await;
+        }
+    }
+
+    pub fn clear(&self) {
+        self.state.requests.lock().unwrap().clear();
+    }
+}
+
+impl Drop for MockHub {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::Configuration;
+
+    #[tokio::test]
+    async fn records_authentication_requests() {
+        let hub_config = Configuration::default().hub_config;
+        let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let hub = MockHub::start(any_port, any_port, &hub_config)
+            .await
+            .unwrap();
+        let base_url = format!("http://{}{}", hub.http_address(), hub_config.auth_path);
+        let client = reqwest::Client::new();
+
+        let agent_id: String = client
+            .post(&base_url)
+            .json("{}")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(agent_id, MOCK_AGENT_ID);
+        let response = client
+            .post(format!(
+                "http://{}/gateway/auth/AOTH/{agent_id}/disconnect",
+                hub.http_address()
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        assert_eq!(
+            hub.requests(),
+            vec![
+                MockHubRequest::Auth {
+                    agent_id: None,
+                    body: String::from("{}"),
+                },
+                MockHubRequest::Disconnect { agent_id },
+            ]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+
+/// Ownership, permissions and identity of a file on Unix systems.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PosixMetadata {
+    pub uid: u32,
+    pub gid: u32,
+    /// Name of the owner, None when the uid has no user (e.g. a deleted account)
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Permission bits, including the setuid, setgid and sticky bits
+    pub mode: u32,
+    pub inode: u64,
+    pub device: u64,
+    pub hard_links: u64,
+}
+
+/// Device and inode of a file, shared by all of its hard links
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId {
+    pub device: u64,
+    pub inode: u64,
+}
+
+impl FileId {
+    #[cfg(unix)]
+    pub fn new(md: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        Some(Self {
+            device: md.dev(),
+            inode: md.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_md: &Metadata) -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(unix)]
+mod names {
+    use lazy_static::lazy_static;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    lazy_static! {
+        // Resolving a name reads the user database, the results are kept for the agent lifetime
+        static ref USER_NAMES: Mutex<HashMap<u32, Option<String>>> = Mutex::new(HashMap::new());
+        static ref GROUP_NAMES: Mutex<HashMap<u32, Option<String>>> = Mutex::new(HashMap::new());
+    }
+
+    pub fn user_name(uid: u32) -> Option<String> {
+        USER_NAMES
+            .lock()
+            .unwrap()
+            .entry(uid)
+            .or_insert_with(|| {
+                uzers::get_user_by_uid(uid).map(|user| user.name().to_string_lossy().into_owned())
+            })
+            .clone()
+    }
+
+    pub fn group_name(gid: u32) -> Option<String> {
+        GROUP_NAMES
+            .lock()
+            .unwrap()
+            .entry(gid)
+            .or_insert_with(|| {
+                uzers::get_group_by_gid(gid)
+                    .map(|group| group.name().to_string_lossy().into_owned())
+            })
+            .clone()
+    }
+}
+
+impl PosixMetadata {
+    #[cfg(unix)]
+    pub fn new(md: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        Some(Self {
+            uid: md.uid(),
+            gid: md.gid(),
+            user: names::user_name(md.uid()),
+            group: names::group_name(md.gid()),
+            mode: md.mode() & 0o7777,
+            inode: md.ino(),
+            device: md.dev(),
+            hard_links: md.nlink(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_md: &Metadata) -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn reads_ownership_and_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "content").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o646)).unwrap();
+        fs::hard_link(&path, dir.path().join("link")).unwrap();
+
+        let posix = PosixMetadata::new(&fs::metadata(&path).unwrap()).unwrap();
+        assert_eq!(posix.mode, 0o646);
+        assert_eq!(posix.hard_links, 2);
+        assert_eq!(posix.uid, uzers::get_current_uid());
+        assert_eq!(
+            posix.user,
+            uzers::get_current_username().map(|name| name.to_string_lossy().into_owned())
+        );
+    }
+}
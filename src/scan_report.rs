@@ -0,0 +1,175 @@
+use crate::error::{AgentError, FileInfoError};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+use tracing::{info, warn};
+
+// The errors are counted by kind, only the first ones are kept with their path
+pub const MAX_REPORTED_ERRORS: usize = 100;
+// Past this many, the paths a scan could not read are only counted
+pub const MAX_SKIPPED_PATHS: usize = 10_000;
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub kind: String,
+    pub message: String,
+}
+
+/// The paths a scan could not read, the files under them may still exist. Only the first
+/// `MAX_SKIPPED_PATHS` are kept, past them any path may have been skipped.
+#[derive(Debug, Clone, Default)]
+pub struct SkippedPaths {
+    paths: BTreeSet<PathBuf>,
+    overflow: usize,
+}
+
+impl SkippedPaths {
+    fn insert(&mut self, path: &Path) {
+        if self.paths.len() < MAX_SKIPPED_PATHS {
+            self.paths.insert(path.to_path_buf());
+        } else if !self.paths.contains(path) {
+            self.overflow += 1;
+        }
+    }
+
+    /// Whether `path` could not be read, or is under a directory or archive that could not be
+    pub fn contains(&self, path: &Path) -> bool {
+        self.overflow > 0
+            || path
+                .ancestors()
+                .any(|ancestor| self.paths.contains(ancestor))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.overflow == 0
+    }
+}
+
+/// What a scan found and what it could not read. A directory that cannot be listed is skipped
+/// with its content and a file that cannot be read is left out, the scan goes on with the rest.
+#[derive(Debug, Serialize, Clone)]
+pub struct ScanReport {
+    pub directories: Vec<PathBuf>,
+    pub started_at: SystemTime,
+    pub duration_ms: u64,
+    /// Files described, archive entries included
+    pub files_found: usize,
+    /// Files found that could not be read
+    pub files_skipped: usize,
+    pub directories_scanned: usize,
+    /// Directories that could not be listed, their content is missing from the scan
+    pub directories_skipped: usize,
+    pub errors_by_kind: BTreeMap<String, usize>,
+    /// First errors of the scan, see `MAX_REPORTED_ERRORS`
+    pub errors: Vec<ScanError>,
+    /// Paths of every error, not only the reported ones
+    #[serde(skip)]
+    pub skipped_paths: SkippedPaths,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl ScanReport {
+    pub fn new(directories: &[PathBuf]) -> Self {
+        Self {
+            directories: directories.to_vec(),
+            started_at: SystemTime::now(),
+            duration_ms: 0,
+            files_found: 0,
+            files_skipped: 0,
+            directories_scanned: 0,
+            directories_skipped: 0,
+            errors_by_kind: BTreeMap::new(),
+            errors: Vec::new(),
+            skipped_paths: SkippedPaths::default(),
+            started: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, path: &Path, kind: String, message: String) {
+        warn!("Scan error on {}: {message}", path.display());
+        *self.errors_by_kind.entry(kind.clone()).or_default() += 1;
+        self.skipped_paths.insert(path);
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(ScanError {
+                path: path.to_path_buf(),
+                kind,
+                message,
+            });
+        }
+    }
+
+    pub fn record_io_error(&mut self, path: &Path, err: &io::Error) {
+        self.record(path, format!("{:?}", err.kind()), err.to_string());
+    }
+
+    pub fn record_skipped_directory(&mut self, path: &Path, err: &io::Error) {
+        self.directories_skipped += 1;
+        self.record_io_error(path, err);
+    }
+
+    /// Records a watched directory that could not be scanned at all
+    pub fn record_skipped_root(&mut self, path: &Path, err: &AgentError) {
+        self.directories_skipped += 1;
+        let kind = match err {
+            AgentError::Io(err) => format!("{:?}", err.kind()),
+            AgentError::NotADirectory() => String::from("NotADirectory"),
+            _ => String::from("Other"),
+        };
+        self.record(path, kind, err.to_string());
+    }
+
+    pub fn record_file_error(&mut self, err: &FileInfoError) {
+        let (path, kind) = match err {
+            FileInfoError::Io(path, err) => (path, format!("{:?}", err.kind())),
+            FileInfoError::ChangedWhileHashing(path) => (path, String::from("ChangedWhileHashing")),
+            FileInfoError::Archive(path, _) => (path, String::from("Archive")),
+            FileInfoError::ArchiveLimit(path, _) => (path, String::from("ArchiveLimit")),
+        };
+        self.record(path, kind, err.to_string());
+    }
+
+    pub fn record_skipped_file(&mut self, err: &FileInfoError) {
+        self.files_skipped += 1;
+        self.record_file_error(err);
+    }
+
+    /// Stops the clock and logs the summary of the scan.
+    pub fn finish(&mut self) {
+        self.duration_ms = self.started.elapsed().as_millis() as u64;
+        info!(
+            "Scanned {} directories in {} ms: {} files found, {} files and {} directories skipped, errors {:?}",
+            self.directories_scanned,
+            self.duration_ms,
+            self.files_found,
+            self.files_skipped,
+            self.directories_skipped,
+            self.errors_by_kind
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_paths_are_only_counted_past_the_cap() {
+        let mut report = ScanReport::new(&[PathBuf::from("/w")]);
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        for index in 0..MAX_SKIPPED_PATHS {
+            report.record_io_error(&PathBuf::from(format!("/w/{index}")), &denied);
+        }
+        assert!(report.skipped_paths.contains(Path::new("/w/0/file")));
+        assert!(!report.skipped_paths.contains(Path::new("/w/readable")));
+
+        report.record_io_error(Path::new("/w/one-too-many"), &denied);
+        assert_eq!(report.skipped_paths.paths.len(), MAX_SKIPPED_PATHS);
+        assert_eq!(report.errors.len(), MAX_REPORTED_ERRORS);
+        // The path is not kept, any other may be the one skipped
+        assert!(report.skipped_paths.contains(Path::new("/w/readable")));
+    }
+}
@@ -1,6 +1,14 @@
 use crate::agent_data::AgentData;
 use crate::configuration;
-use crate::http::routes::{get_config, get_status, AgentDataState, GlobalConfigState};
+use crate::duplicates::DuplicateIndex;
+use crate::event_pipeline::PipelineCounters;
+use crate::hash_cache::HashCache;
+use crate::http::grpc::GrpcStatus;
+use crate::http::routes::{
+    get_config, get_duplicates, get_metrics, get_scan_report, get_status, AgentDataState,
+    DuplicatesState, GlobalConfigState, MetricsState, ScanReportState,
+};
+use crate::scan_report::ScanReport;
 use axum::{routing::get, Router};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
@@ -33,11 +41,11 @@ pub struct Server {
 pub struct ServerBuilder {
     router: Router,
     global_configuration: configuration::Configuration,
-}
-
-trait ServerConfig {
-    fn host(&self) -> &str;
-    fn route(&self) -> &str;
+    grpc_status: Arc<Mutex<GrpcStatus>>,
+    pipeline_counters: Arc<PipelineCounters>,
+    hash_cache: Arc<HashCache>,
+    duplicates: Arc<Mutex<DuplicateIndex>>,
+    scan_report: Arc<Mutex<Option<ScanReport>>>,
 }
 
 impl ServerBuilder {
@@ -53,6 +61,31 @@ impl ServerBuilder {
         self
     }
 
+    pub fn inject_pipeline_counters(mut self, pipeline_counters: Arc<PipelineCounters>) -> Self {
+        self.pipeline_counters = pipeline_counters;
+        self
+    }
+
+    pub fn inject_hash_cache(mut self, hash_cache: Arc<HashCache>) -> Self {
+        self.hash_cache = hash_cache;
+        self
+    }
+
+    pub fn inject_duplicates(mut self, duplicates: Arc<Mutex<DuplicateIndex>>) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    pub fn inject_scan_report(mut self, scan_report: Arc<Mutex<Option<ScanReport>>>) -> Self {
+        self.scan_report = scan_report;
+        self
+    }
+
+    pub fn inject_grpc_status(mut self, grpc_status: Arc<Mutex<GrpcStatus>>) -> Self {
+        self.grpc_status = grpc_status;
+        self
+    }
+
     pub fn build(
         self,
         latest_version: String,
@@ -67,10 +100,21 @@ impl ServerBuilder {
                 minimal_version,
                 dirs_watch,
             ))),
+            grpc_status: self.grpc_status,
         };
         let global_config_state = GlobalConfigState {
             config: self.global_configuration,
         };
+        let metrics_state = MetricsState {
+            pipeline_counters: self.pipeline_counters,
+            hash_cache: self.hash_cache,
+        };
+        let duplicates_state = DuplicatesState {
+            duplicates: self.duplicates,
+        };
+        let scan_report_state = ScanReportState {
+            scan_report: self.scan_report,
+        };
 
         let server_logging_level: Level = AGENT_LOGGING_LEVEL.get(logging_level).map_or_else(
             || {
@@ -87,6 +131,15 @@ impl ServerBuilder {
             .router
             .route("/get_status", get(get_status).with_state(agent_data_state))
             .route("/config", get(get_config).with_state(global_config_state))
+            .route("/metrics", get(get_metrics).with_state(metrics_state))
+            .route(
+                "/duplicates",
+                get(get_duplicates).with_state(duplicates_state),
+            )
+            .route(
+                "/scan_report",
+                get(get_scan_report).with_state(scan_report_state),
+            )
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(trace::DefaultMakeSpan::new().level(server_logging_level))
@@ -0,0 +1,267 @@
+use crate::event_pipeline::PipelineCounters;
+use notify::event::{AccessKind, AccessMode, CreateKind, EventKind, ModifyKind, RenameMode};
+use notify_debouncer_full::DebouncedEvent;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Size and modification time, a file whose version does not change is no longer written to
+type FileVersion = (u64, Option<SystemTime>);
+
+fn file_version(path: &Path) -> Option<FileVersion> {
+    let md = fs::metadata(path).ok()?;
+    md.is_file().then(|| (md.len(), md.modified().ok()))
+}
+
+struct PendingWrite {
+    // First event of the write, the following ones are merged into it
+    event: DebouncedEvent,
+    version: FileVersion,
+    stable_since: Instant,
+}
+
+/// Holds back the creations and modifications of a file until it is completely written, so that
+/// a file being copied is hashed once instead of after every chunk.
+///
+/// A file is complete once its writer closed it, or when its size and modification time did not
+/// change for the quiet period on platforms that do not report closes.
+pub struct StableWrites {
+    quiet_period: Duration,
+    pending: HashMap<PathBuf, PendingWrite>,
+    counters: Arc<PipelineCounters>,
+}
+
+impl StableWrites {
+    /// A zero quiet period forwards every event as soon as it is received.
+    pub fn new(quiet_period: Duration, counters: Arc<PipelineCounters>) -> Self {
+        Self {
+            quiet_period,
+            pending: HashMap::new(),
+            counters,
+        }
+    }
+
+    /// Returns the events to forward now, the writes are held back until `poll` finds them stable.
+    pub fn filter(&mut self, event: DebouncedEvent, now: Instant) -> Vec<DebouncedEvent> {
+        if self.quiet_period.is_zero() {
+            return vec![event];
+        }
+        match event.kind {
+            EventKind::Create(CreateKind::File) | EventKind::Modify(ModifyKind::Data(_)) => {
+                let path = event.paths[0].clone();
+                let version = match file_version(&path) {
+                    Some(version) => version,
+                    None => return vec![event],
+                };
+                match self.pending.get_mut(&path) {
+                    Some(pending) => {
+                        self.counters.record_merged_write();
+                        if pending.version != version {
+                            pending.version = version;
+                            pending.stable_since = now;
+                        }
+                    }
+                    None => {
+                        self.pending.insert(
+                            path,
+                            PendingWrite {
+                                event,
+                                version,
+                                stable_since: now,
+                            },
+                        );
+                    }
+                }
+                Vec::new()
+            }
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => self
+                .pending
+                .remove(&event.paths[0])
+                .map(|pending| pending.event)
+                .into_iter()
+                .collect(),
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                let (from, to) = match event.paths.as_slice() {
+                    [from, to, ..] => (from.clone(), to.clone()),
+                    _ => return vec![event],
+                };
+                // The write goes on under the new name
+                let mut renamed_creation = false;
+                for (old_path, mut pending) in self.take_pending_under(&from) {
+                    if let Ok(relative) = old_path.strip_prefix(&from) {
+                        let new_path = to.join(relative);
+                        renamed_creation |= old_path == from
+                            && pending.event.kind == EventKind::Create(CreateKind::File);
+                        pending.event.paths = vec![new_path.clone()];
+                        self.pending.insert(new_path, pending);
+                    }
+                }
+                if renamed_creation {
+                    // The Hub does not know the file yet, only its creation under the new name
+                    // is sent
+                    self.counters.record_merged_write();
+                    return Vec::new();
+                }
+                vec![event]
+            }
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                // The write will never complete, the removal is enough for the Hub
+                let path = &event.paths[0];
+                let removed_creation =
+                    self.take_pending_under(path).iter().any(|(held, pending)| {
+                        held == path && pending.event.kind == EventKind::Create(CreateKind::File)
+                    });
+                if removed_creation {
+                    // The Hub never heard of the file, neither its creation nor its removal
+                    // is sent
+                    self.counters.record_merged_write();
+                    return Vec::new();
+                }
+                vec![event]
+            }
+            _ => vec![event],
+        }
+    }
+
+    /// Releases the writes that did not change for the quiet period, in the order they started.
+    pub fn poll(&mut self, now: Instant) -> Vec<DebouncedEvent> {
+        let mut stable = Vec::new();
+        self.pending
+            .retain(|path, pending| match file_version(path) {
+                // Removed or replaced by a directory, its own event tells the Hub
+                None => false,
+                Some(version) if version != pending.version => {
+                    pending.version = version;
+                    pending.stable_since = now;
+                    true
+                }
+                Some(_) if now.duration_since(pending.stable_since) >= self.quiet_period => {
+                    stable.push(pending.event.clone());
+                    false
+                }
+                Some(_) => true,
+            });
+        stable.sort_by_key(|event| event.time);
+        stable
+    }
+
+    fn take_pending_under(&mut self, path: &Path) -> Vec<(PathBuf, PendingWrite)> {
+        let paths: Vec<PathBuf> = self
+            .pending
+            .keys()
+            .filter(|pending_path| pending_path.starts_with(path))
+            .cloned()
+            .collect();
+        paths
+            .into_iter()
+            .filter_map(|path| self.pending.remove_entry(&path))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{DataChange, RemoveKind};
+    use notify::Event;
+
+    fn event(kind: EventKind, path: &Path) -> DebouncedEvent {
+        DebouncedEvent::new(
+            Event::new(kind).add_path(path.to_path_buf()),
+            Instant::now(),
+        )
+    }
+
+    fn stable_writes() -> StableWrites {
+        StableWrites::new(
+            Duration::from_secs(1),
+            Arc::new(PipelineCounters::default()),
+        )
+    }
+
+    #[test]
+    fn waits_for_the_file_to_stop_changing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("download");
+        let mut stable_writes = stable_writes();
+        let start = Instant::now();
+
+        fs::write(&path, "part").unwrap();
+        assert!(stable_writes
+            .filter(event(EventKind::Create(CreateKind::File), &path), start)
+            .is_empty());
+        fs::write(&path, "part and more").unwrap();
+        let modified = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        assert!(stable_writes
+            .filter(event(modified, &path), start + Duration::from_millis(800))
+            .is_empty());
+        assert!(stable_writes
+            .poll(start + Duration::from_millis(1200))
+            .is_empty());
+
+        let released = stable_writes.poll(start + Duration::from_millis(1800));
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].kind, EventKind::Create(CreateKind::File));
+        assert_eq!(stable_writes.counters.snapshot().merged_writes, 1);
+    }
+
+    #[test]
+    fn close_after_write_releases_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let renamed = dir.path().join("renamed");
+        let mut stable_writes = stable_writes();
+        let now = Instant::now();
+
+        fs::write(&path, "content").unwrap();
+        stable_writes.filter(event(EventKind::Create(CreateKind::File), &path), now);
+        let rename = DebouncedEvent::new(
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                .add_path(path.clone())
+                .add_path(renamed.clone()),
+            now,
+        );
+        assert!(stable_writes.filter(rename, now).is_empty());
+
+        let closed = EventKind::Access(AccessKind::Close(AccessMode::Write));
+        let released = stable_writes.filter(event(closed, &renamed), now);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].kind, EventKind::Create(CreateKind::File));
+        assert_eq!(released[0].paths, vec![renamed]);
+        assert!(stable_writes.pending.is_empty());
+    }
+
+    #[test]
+    fn file_removed_before_its_write_completes_is_not_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let (created, modified) = (dir.path().join("created"), dir.path().join("modified"));
+        fs::write(&modified, "known").unwrap();
+        let mut stable_writes = stable_writes();
+        let now = Instant::now();
+
+        fs::write(&created, "content").unwrap();
+        stable_writes.filter(event(EventKind::Create(CreateKind::File), &created), now);
+        fs::remove_file(&created).unwrap();
+        let removed = EventKind::Remove(RemoveKind::File);
+        assert!(stable_writes
+            .filter(event(removed, &created), now + Duration::from_millis(500))
+            .is_empty());
+
+        // The Hub knows a modified file, its removal is sent
+        fs::write(&modified, "known and more").unwrap();
+        let data = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        stable_writes.filter(event(data, &modified), now);
+        fs::remove_file(&modified).unwrap();
+        let released =
+            stable_writes.filter(event(removed, &modified), now + Duration::from_millis(500));
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].kind, removed);
+
+        assert!(stable_writes.pending.is_empty());
+        assert!(stable_writes
+            .poll(now + Duration::from_millis(2000))
+            .is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+use crate::file_info::fix_canonicalize_path;
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// A directory watched by the agent. Its id is derived from its canonical path, so it stays the
+/// same across restarts and does not reveal the path itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchedRoot {
+    pub id: String,
+    pub path: PathBuf,
+}
+
+impl WatchedRoot {
+    /// Resolves the directory, None when it does not exist.
+    pub fn new(directory: &Path) -> Option<Self> {
+        let path = fix_canonicalize_path(directory.canonicalize().ok()?);
+        Some(Self {
+            id: format!("{:016x}", xxh3_64(path.as_os_str().as_encoded_bytes())),
+            path,
+        })
+    }
+}
+
+/// A path located in a watched directory
+#[derive(Debug, PartialEq, Eq)]
+pub struct RootedPath<'a> {
+    pub root_id: Option<&'a str>,
+    /// Path relative to the watched directory, only the file name when outside of every watched
+    /// directory
+    pub pretty_path: &'a Path,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct WatchedRoots {
+    roots: Vec<WatchedRoot>,
+}
+
+impl WatchedRoots {
+    pub fn new(directories: &[PathBuf]) -> Self {
+        let mut roots = Self::default();
+        for directory in directories {
+            roots.add(directory);
+        }
+        roots
+    }
+
+    /// Adds a directory, returns the resolved root unless it does not exist.
+    pub fn add(&mut self, directory: &Path) -> Option<&WatchedRoot> {
+        let root = WatchedRoot::new(directory)?;
+        let index = match self.roots.iter().position(|known| *known == root) {
+            Some(index) => index,
+            None => {
+                self.roots.push(root);
+                self.roots.len() - 1
+            }
+        };
+        Some(&self.roots[index])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &WatchedRoot> {
+        self.roots.iter()
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.root_of(path).is_some()
+    }
+
+    /// Innermost watched directory containing `path`, roots may be nested
+    pub fn root_of(&self, path: &Path) -> Option<&WatchedRoot> {
+        self.roots
+            .iter()
+            .filter(|root| path.starts_with(&root.path))
+            .max_by_key(|root| root.path.components().count())
+    }
+
+    pub fn locate<'a>(&'a self, path: &'a Path) -> RootedPath<'a> {
+        match self.root_of(path) {
+            Some(root) => RootedPath {
+                root_id: Some(&root.id),
+                pretty_path: path.strip_prefix(&root.path).unwrap_or(path),
+            },
+            // The full path would reveal what is outside of the watched directories
+            None => RootedPath {
+                root_id: None,
+                pretty_path: path.file_name().map_or(Path::new(""), Path::new),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn paths_are_relative_to_the_innermost_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let outer = dir.path().join("outer");
+        let inner = outer.join("inner");
+        fs::create_dir_all(&inner).unwrap();
+
+        let roots = WatchedRoots::new(&[outer.clone(), inner.clone(), dir.path().join("missing")]);
+        assert_eq!(roots.iter().count(), 2);
+        let outer = WatchedRoot::new(&outer).unwrap();
+        let inner = WatchedRoot::new(&inner).unwrap();
+        assert_ne!(outer.id, inner.id);
+        assert_eq!(WatchedRoot::new(&outer.path).unwrap().id, outer.id);
+
+        let file = inner.path.join("sub/file.txt");
+        assert_eq!(
+            roots.locate(&file),
+            RootedPath {
+                root_id: Some(&inner.id),
+                pretty_path: Path::new("sub/file.txt"),
+            }
+        );
+        assert_eq!(
+            roots.locate(&outer.path.join("file.txt")).pretty_path,
+            Path::new("file.txt")
+        );
+        assert_eq!(
+            roots.locate(Path::new("/elsewhere/file.txt")),
+            RootedPath {
+                root_id: None,
+                pretty_path: Path::new("file.txt"),
+            }
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tidybee_agent::configuration::Configuration;
+use tidybee_agent::mock_hub::tidybee_events::{FileCategory, FileEventRequest, FileEventType};
+use tidybee_agent::mock_hub::{MockHub, MockHubRequest};
+
+const EVENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn has_event(requests: &[MockHubRequest], event_type: FileEventType, path: &Path) -> bool {
+    requests.iter().any(|request| match request {
+        MockHubRequest::FileEvent(event) => is_event(event, event_type, path),
+        _ => false,
+    })
+}
+
+fn is_event(event: &FileEventRequest, event_type: FileEventType, path: &Path) -> bool {
+    event.event_type == event_type as i32 && event.path == vec![path.display().to_string()]
+}
+
+fn event_types(hub: &MockHub) -> Vec<(FileEventType, String)> {
+    hub.file_events()
+        .into_iter()
+        .map(|event| (event.event_type(), event.path.join(",")))
+        .collect()
+}
+
+// The agent keeps its uuid in `config/uuid` relative to the working directory, so the whole
+// scenario runs in a single test from a temporary directory.
+#[tokio::test(flavor = "multi_thread")]
+async fn agent_reports_file_changes_to_the_hub() {
+    let root = tempfile::tempdir().unwrap();
+    let root_path = root.path().canonicalize().unwrap();
+    std::env::set_current_dir(&root_path).unwrap();
+    fs::create_dir(root_path.join("config")).unwrap();
+    let watched = root_path.join("watched");
+    fs::create_dir(&watched).unwrap();
+    let existing = watched.join("existing.txt");
+    fs::write(&existing, "existing").unwrap();
+
+    let mut config = Configuration::default();
+    let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let hub = MockHub::start(any_port, any_port, &config.hub_config)
+        .await
+        .unwrap();
+    hub.configure(&mut config.hub_config);
+    config.hub_config.grpc_server.flush_interval_ms = 100;
+    config.filesystem_interface_config.dir = vec![watched.clone()];
+    config.state_config.dir = root_path.join("state");
+    // Saved well within the pause before the restart
+    config.state_config.save_interval_ms = 100;
+    config.server_config.address = String::from("127.0.0.1:0");
+    let agent = tokio::spawn(tidybee_agent::run_with_configuration(config.clone()));
+
+    // Initial scan
+    assert!(
+        hub.wait_for(EVENT_TIMEOUT, |requests| has_event(
+            requests,
+            FileEventType::Created,
+            &existing
+        ))
+        .await
+    );
+    assert!(matches!(
+        hub.requests()[0],
+        MockHubRequest::Auth { agent_id: None, .. }
+    ));
+    // Give the watcher time to start once the initial scan was sent
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    hub.clear();
+
+    let created = watched.join("created.txt");
+    fs::write(&created, "created").unwrap();
+    assert!(
+        hub.wait_for(EVENT_TIMEOUT, |requests| has_event(
+            requests,
+            FileEventType::Created,
+            &created
+        ))
+        .await,
+        "{:?}",
+        event_types(&hub)
+    );
+    let event = hub
+        .file_events()
+        .into_iter()
+        .find(|event| is_event(event, FileEventType::Created, &created))
+        .unwrap();
+    assert_eq!(event.size, Some(7));
+    assert_eq!(event.hash.as_ref().map(String::len), Some(32));
+    assert_eq!(event.hash_algorithm.as_deref(), Some("xxh3-128"));
+    assert_eq!(event.mime_type.as_deref(), Some("text/plain"));
+    assert_eq!(event.category(), FileCategory::Document);
+    assert_eq!(event.posix.as_ref().map(|posix| posix.hard_links), Some(1));
+    assert_eq!(event.pretty_path, "created.txt");
+    assert_eq!(event.root_id.as_ref().map(String::len), Some(16));
+    hub.clear();
+
+    // Modifications are reported as an update of the file with its new content
+    fs::write(&created, "created and modified").unwrap();
+    assert!(
+        hub.wait_for(EVENT_TIMEOUT, |requests| {
+            requests.iter().any(|request| {
+                matches!(request, MockHubRequest::FileEvent(event)
+                if is_event(event, FileEventType::Updated, &created) && event.size == Some(20))
+            })
+        })
+        .await,
+        "{:?}",
+        event_types(&hub)
+    );
+    hub.clear();
+
+    let renamed = watched.join("renamed.txt");
+    fs::rename(&created, &renamed).unwrap();
+    assert!(
+        hub.wait_for(EVENT_TIMEOUT, |requests| has_event(
+            requests,
+            FileEventType::Created,
+            &renamed
+        ))
+        .await,
+        "{:?}",
+        event_types(&hub)
+    );
+    assert_eq!(
+        event_types(&hub),
+        vec![
+            (FileEventType::Deleted, created.display().to_string()),
+            (FileEventType::Created, renamed.display().to_string()),
+        ]
+    );
+    hub.clear();
+
+    fs::remove_file(&renamed).unwrap();
+    assert!(
+        hub.wait_for(EVENT_TIMEOUT, |requests| has_event(
+            requests,
+            FileEventType::Deleted,
+            &renamed
+        ))
+        .await,
+        "{:?}",
+        event_types(&hub)
+    );
+    assert_eq!(
+        event_types(&hub),
+        vec![(FileEventType::Deleted, renamed.display().to_string())]
+    );
+
+    // Let the inventory be saved with the changes seen by the watcher, then change the files
+    // while the agent is down
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    agent.abort();
+    hub.clear();
+    fs::remove_file(&existing).unwrap();
+    let added = watched.join("added.txt");
+    fs::write(&added, "added").unwrap();
+
+    // The restarted agent only reports the changes, the deletions once the scan is over
+    let agent = tokio::spawn(tidybee_agent::run_with_configuration(config));
+    assert!(
+        hub.wait_for(EVENT_TIMEOUT, |requests| has_event(
+            requests,
+            FileEventType::Deleted,
+            &existing
+        ))
+        .await,
+        "{:?}",
+        event_types(&hub)
+    );
+    assert_eq!(
+        event_types(&hub),
+        vec![
+            (FileEventType::Created, added.display().to_string()),
+            (FileEventType::Deleted, existing.display().to_string()),
+        ]
+    );
+    agent.abort();
+}